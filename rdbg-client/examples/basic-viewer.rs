@@ -11,6 +11,9 @@ fn main() {
                 Event::Connected(addr) => {
                     eprintln!("*** Connected to {addr} ***");
                 }
+                Event::Hello(hello) => {
+                    eprintln!("*** {hello:#?} ***");
+                }
                 Event::Disconnected(addr) => {
                     eprintln!("*** Disconnected from {addr} ***");
                 }
@@ -18,9 +21,9 @@ fn main() {
                     println!("{msg:#?}");
                 }
             },
-            Err(err) => match err {
+            Err(err) => match &err {
                 Error::BadVersion => {
-                    eprintln!("*** Bad version (we only understand wire protocol 1) ***");
+                    eprintln!("*** Bad version ({err}) ***");
                     break;
                 }
                 Error::BadUtf8(err) => {
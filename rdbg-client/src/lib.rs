@@ -1,8 +1,16 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
 use std::io::Read;
 use std::mem::size_of;
-use std::net::{AddrParseError, IpAddr, SocketAddr, TcpStream};
+use std::net::{AddrParseError, IpAddr, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
 use std::str::{FromStr, Utf8Error};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::Duration;
 use std::{io, thread};
 
@@ -14,7 +22,12 @@ pub const DEFAULT_PORT: u16 = 13579;
 const CONNECT_WAIT_TIME: u64 = 250; // Milliseconds
 const BUFFER_SIZE: usize = 4096;
 const LEN_FIELD_SIZE: usize = size_of::<u32>();
-const WIRE_PROTOCOL_VERSION: u8 = 1;
+const WIRE_PROTOCOL_VERSION: u8 = 5;
+// Deeper values than this are considered corrupt (keeps decoding from overflowing the stack)
+const MAX_VALUE_DEPTH: usize = 128;
+// Largest record (in bytes) or count of items we will allocate room for, so a corrupt length
+// can't make us allocate a huge amount of memory before bounds checking catches it
+const MAX_LEN: usize = 16 * 1024 * 1024;
 
 // *** MsgPayloadVal ***
 
@@ -22,6 +35,22 @@ const WIRE_PROTOCOL_VERSION: u8 = 1;
 enum MsgPayloadVal {
     Message = 1,
     Values = 2,
+    Dropped = 3,
+    HistoryEnd = 4,
+    SpanNew = 5,
+    SpanEnter = 6,
+    SpanExit = 7,
+    SpanClose = 8,
+    SpanRecord = 9,
+    Event = 10,
+    Panic = 11,
+    Backtrace = 12,
+    Timing = 13,
+    Counter = 14,
+    Gauge = 15,
+    StructuredValues = 16,
+    Bytes = 17,
+    Table = 18,
 }
 
 impl MsgPayloadVal {
@@ -38,11 +67,95 @@ impl TryFrom<u8> for MsgPayloadVal {
         match value {
             1 => Ok(MsgPayloadVal::Message),
             2 => Ok(MsgPayloadVal::Values),
+            3 => Ok(MsgPayloadVal::Dropped),
+            4 => Ok(MsgPayloadVal::HistoryEnd),
+            5 => Ok(MsgPayloadVal::SpanNew),
+            6 => Ok(MsgPayloadVal::SpanEnter),
+            7 => Ok(MsgPayloadVal::SpanExit),
+            8 => Ok(MsgPayloadVal::SpanClose),
+            9 => Ok(MsgPayloadVal::SpanRecord),
+            10 => Ok(MsgPayloadVal::Event),
+            11 => Ok(MsgPayloadVal::Panic),
+            12 => Ok(MsgPayloadVal::Backtrace),
+            13 => Ok(MsgPayloadVal::Timing),
+            14 => Ok(MsgPayloadVal::Counter),
+            15 => Ok(MsgPayloadVal::Gauge),
+            16 => Ok(MsgPayloadVal::StructuredValues),
+            17 => Ok(MsgPayloadVal::Bytes),
+            18 => Ok(MsgPayloadVal::Table),
             _ => Err(Error::CorruptMsg),
         }
     }
 }
 
+// *** Level ***
+
+/// The severity of a [Message]. Ordered from most to least severe, so `Level::Error` is the
+/// smallest value.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    #[inline]
+    fn from_buffer(buffer: &mut ByteBuffer) -> Result<Level, Error> {
+        buffer.read_u8()?.try_into()
+    }
+
+    /// The name of the level in upper case
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl TryFrom<u8> for Level {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        match value {
+            1 => Ok(Level::Error),
+            2 => Ok(Level::Warn),
+            3 => Ok(Level::Info),
+            4 => Ok(Level::Debug),
+            5 => Ok(Level::Trace),
+            _ => Err(Error::CorruptMsg),
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for Level {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
 // *** ByteBuffer ***
 
 struct ByteBuffer {
@@ -61,7 +174,7 @@ impl ByteBuffer {
         Self { buffer, idx: 0 }
     }
 
-    fn read_from_stream(&mut self, stream: &mut TcpStream, size: usize) -> io::Result<()> {
+    fn read_from_stream(&mut self, stream: &mut impl Read, size: usize) -> io::Result<()> {
         self.buffer.resize(size, 0);
         stream.read_exact(&mut self.buffer)?;
         // We start over every time we read
@@ -90,12 +203,29 @@ impl ByteBuffer {
         ))
     }
 
+    fn read_u128(&mut self) -> Result<u128, Error> {
+        Ok(u128::from_be_bytes(
+            self.as_slice(size_of::<u128>())?.try_into().unwrap(),
+        ))
+    }
+
     fn read_u32(&mut self) -> Result<u32, Error> {
         Ok(u32::from_be_bytes(
             self.as_slice(size_of::<u32>())?.try_into().unwrap(),
         ))
     }
 
+    // Reads the length of a list of items, which is checked before it is used to allocate
+    #[inline]
+    fn read_len(&mut self) -> Result<usize, Error> {
+        check_len(self.read_u32()?)
+    }
+
+    fn read_bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = self.read_u32()?;
+        Ok(self.as_slice(len as usize)?.to_vec())
+    }
+
     fn read_str(&mut self) -> Result<String, Error> {
         let len = self.read_u32()?;
 
@@ -104,86 +234,807 @@ impl ByteBuffer {
             Err(err) => Err(Error::BadUtf8(err)),
         }
     }
+
+    fn read_row(&mut self) -> Result<Vec<String>, Error> {
+        let len = self.read_len()?;
+        let mut row = Vec::with_capacity(len);
+
+        for _ in 0..len {
+            row.push(self.read_str()?);
+        }
+
+        Ok(row)
+    }
+
+    fn read_values(&mut self) -> Result<Vec<(String, String)>, Error> {
+        let len = self.read_len()?;
+        let mut values = Vec::with_capacity(len);
+
+        for _ in 0..len {
+            let name = self.read_str()?;
+            let val = self.read_str()?;
+            values.push((name, val));
+        }
+
+        Ok(values)
+    }
+
+    fn read_frames(&mut self) -> Result<Vec<Frame>, Error> {
+        let len = self.read_len()?;
+        let mut frames = Vec::with_capacity(len);
+
+        for _ in 0..len {
+            frames.push(Frame::from_buffer(self)?);
+        }
+
+        Ok(frames)
+    }
+
+    fn read_structured_values(&mut self) -> Result<Vec<Value>, Error> {
+        let len = self.read_len()?;
+        let mut values = Vec::with_capacity(len);
+
+        for _ in 0..len {
+            values.push(Value::from_buffer(self, 0)?);
+        }
+
+        Ok(values)
+    }
+
+    // Span IDs are never zero, so that is used to mean "no span"
+    #[inline]
+    fn read_span_id(&mut self) -> Result<Option<u64>, Error> {
+        Ok(Some(self.read_u64()?).filter(|&id| id != 0))
+    }
 }
 
-// *** MsgPayload ***
+// *** Frame ***
 
-/// The payload as sent by the remote program - this can either be a string message or a list
-/// of expressions and their values
+/// A single frame of a backtrace
 #[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Frame {
+    /// The function name (demangled, ex: `my_crate::my_mod::my_func`)
+    pub function: String,
+    /// The source file, if known (relative to the remote program's working directory if within it)
+    pub file: Option<String>,
+    /// The line number in the source file, if known
+    pub line: Option<u32>,
+}
+
+impl Frame {
+    fn from_buffer(buffer: &mut ByteBuffer) -> Result<Self, Error> {
+        let function = buffer.read_str()?;
+        let file = buffer.read_str()?;
+        let line = buffer.read_u32()?;
+
+        // An empty file means the location is unknown
+        Ok(if file.is_empty() {
+            Self {
+                function,
+                file: None,
+                line: None,
+            }
+        } else {
+            Self {
+                function,
+                file: Some(file),
+                line: Some(line),
+            }
+        })
+    }
+}
+
+// *** ValueType ***
+
+#[repr(u8)]
+enum ValueType {
+    Unit = 1,
+    Bool = 2,
+    I64 = 3,
+    U64 = 4,
+    F64 = 5,
+    Char = 6,
+    Str = 7,
+    Bytes = 8,
+    None = 9,
+    Some = 10,
+    Seq = 11,
+    Map = 12,
+    Struct = 13,
+    TupleStruct = 14,
+    Variant = 15,
+}
+
+impl TryFrom<u8> for ValueType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(ValueType::Unit),
+            2 => Ok(ValueType::Bool),
+            3 => Ok(ValueType::I64),
+            4 => Ok(ValueType::U64),
+            5 => Ok(ValueType::F64),
+            6 => Ok(ValueType::Char),
+            7 => Ok(ValueType::Str),
+            8 => Ok(ValueType::Bytes),
+            9 => Ok(ValueType::None),
+            10 => Ok(ValueType::Some),
+            11 => Ok(ValueType::Seq),
+            12 => Ok(ValueType::Map),
+            13 => Ok(ValueType::Struct),
+            14 => Ok(ValueType::TupleStruct),
+            15 => Ok(ValueType::Variant),
+            _ => Err(Error::CorruptMsg),
+        }
+    }
+}
+
+// *** Value ***
+
+/// A structured value as serialized by the remote program (from `rdbg::svals!`). This mirrors
+/// the [serde](https://docs.rs/serde) data model: smaller integers and floats are widened,
+/// newtype structs are transparent, and tuples are sent as sequences.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Unit,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Char(char),
+    /// A string (also used for integers too large for 64 bits and `collect_str` values)
+    Str(String),
+    Bytes(Vec<u8>),
+    Option(Option<Box<Value>>),
+    /// A sequence or tuple
+    Seq(Vec<Value>),
+    /// Map entries in the order they were serialized
+    Map(Vec<(Value, Value)>),
+    /// A struct with named fields (a unit struct has no fields)
+    Struct {
+        name: String,
+        fields: Vec<(String, Value)>,
+    },
+    TupleStruct {
+        name: String,
+        values: Vec<Value>,
+    },
+    /// An enum variant. The value is [Value::Unit] for unit variants, [Value::Seq] for tuple
+    /// variants and [Value::Struct] (named after the variant) for struct variants.
+    Variant {
+        name: String,
+        variant: String,
+        value: Box<Value>,
+    },
+}
+
+impl Value {
+    /// Returns the value of a struct field or a map entry with a string key (looking inside enum
+    /// variants and `Some`), if present
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Struct { fields, .. } => fields
+                .iter()
+                .find_map(|(name, value)| (name == key).then_some(value)),
+            Value::Map(entries) => entries.iter().find_map(|(k, value)| match k {
+                Value::Str(k) if k == key => Some(value),
+                _ => None,
+            }),
+            Value::Variant { value, .. } | Value::Option(Some(value)) => value.get(key),
+            _ => None,
+        }
+    }
+
+    /// Returns the element at `idx` of a sequence or tuple struct (looking inside enum variants
+    /// and `Some`), if present
+    pub fn index(&self, idx: usize) -> Option<&Value> {
+        match self {
+            Value::Seq(values) | Value::TupleStruct { values, .. } => values.get(idx),
+            Value::Variant { value, .. } | Value::Option(Some(value)) => value.index(idx),
+            _ => None,
+        }
+    }
+
+    fn from_buffer(buffer: &mut ByteBuffer, depth: usize) -> Result<Self, Error> {
+        if depth > MAX_VALUE_DEPTH {
+            return Err(Error::CorruptMsg);
+        }
+
+        match ValueType::try_from(buffer.read_u8()?)? {
+            ValueType::Unit => Ok(Value::Unit),
+            ValueType::Bool => Ok(Value::Bool(buffer.read_u8()? != 0)),
+            ValueType::I64 => Ok(Value::I64(buffer.read_u64()? as i64)),
+            ValueType::U64 => Ok(Value::U64(buffer.read_u64()?)),
+            ValueType::F64 => Ok(Value::F64(f64::from_bits(buffer.read_u64()?))),
+            ValueType::Char => char::from_u32(buffer.read_u32()?)
+                .map(Value::Char)
+                .ok_or(Error::CorruptMsg),
+            ValueType::Str => Ok(Value::Str(buffer.read_str()?)),
+            ValueType::Bytes => Ok(Value::Bytes(buffer.read_bytes()?)),
+            ValueType::None => Ok(Value::Option(None)),
+            ValueType::Some => {
+                let value = Self::from_buffer(buffer, depth + 1)?;
+                Ok(Value::Option(Some(Box::new(value))))
+            }
+            ValueType::Seq => Ok(Value::Seq(Self::read_seq(buffer, depth)?)),
+            ValueType::Map => {
+                let len = buffer.read_len()?;
+                let mut entries = Vec::with_capacity(len);
+
+                for _ in 0..len {
+                    let key = Self::from_buffer(buffer, depth + 1)?;
+                    let value = Self::from_buffer(buffer, depth + 1)?;
+                    entries.push((key, value));
+                }
+
+                Ok(Value::Map(entries))
+            }
+            ValueType::Struct => {
+                let name = buffer.read_str()?;
+                let len = buffer.read_len()?;
+                let mut fields = Vec::with_capacity(len);
+
+                for _ in 0..len {
+                    let field = buffer.read_str()?;
+                    let value = Self::from_buffer(buffer, depth + 1)?;
+                    fields.push((field, value));
+                }
+
+                Ok(Value::Struct { name, fields })
+            }
+            ValueType::TupleStruct => {
+                let name = buffer.read_str()?;
+                let values = Self::read_seq(buffer, depth)?;
+                Ok(Value::TupleStruct { name, values })
+            }
+            ValueType::Variant => {
+                let name = buffer.read_str()?;
+                let variant = buffer.read_str()?;
+                let value = Self::from_buffer(buffer, depth + 1)?;
+                Ok(Value::Variant {
+                    name,
+                    variant,
+                    value: Box::new(value),
+                })
+            }
+        }
+    }
+
+    fn read_seq(buffer: &mut ByteBuffer, depth: usize) -> Result<Vec<Value>, Error> {
+        let len = buffer.read_len()?;
+        let mut values = Vec::with_capacity(len);
+
+        for _ in 0..len {
+            values.push(Self::from_buffer(buffer, depth + 1)?);
+        }
+
+        Ok(values)
+    }
+}
+
+fn fmt_list<T>(
+    f: &mut Formatter<'_>,
+    items: &[T],
+    (open, close): (&str, &str),
+    fmt_item: impl Fn(&mut Formatter<'_>, &T) -> std::fmt::Result,
+) -> std::fmt::Result {
+    f.write_str(open)?;
+
+    for (idx, item) in items.iter().enumerate() {
+        if idx > 0 {
+            f.write_str(", ")?;
+        }
+        fmt_item(f, item)?;
+    }
+
+    f.write_str(close)
+}
+
+/// Formats the value on a single line, in a syntax similar to Rust's `Debug` output
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Unit => f.write_str("()"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::I64(i) => write!(f, "{i}"),
+            Value::U64(u) => write!(f, "{u}"),
+            Value::F64(float) => write!(f, "{float:?}"),
+            Value::Char(c) => write!(f, "{c:?}"),
+            Value::Str(s) => write!(f, "{s:?}"),
+            Value::Bytes(bytes) => write!(f, "{bytes:?}"),
+            Value::Option(None) => f.write_str("None"),
+            Value::Option(Some(value)) => write!(f, "Some({value})"),
+            Value::Seq(values) => fmt_list(f, values, ("[", "]"), |f, v| write!(f, "{v}")),
+            Value::Map(entries) => {
+                fmt_list(f, entries, ("{", "}"), |f, (k, v)| write!(f, "{k}: {v}"))
+            }
+            Value::Struct { name, fields } if fields.is_empty() => f.write_str(name),
+            Value::Struct { name, fields } => {
+                f.write_str(name)?;
+                fmt_list(f, fields, (" { ", " }"), |f, (k, v)| write!(f, "{k}: {v}"))
+            }
+            Value::TupleStruct { name, values } => {
+                f.write_str(name)?;
+                fmt_list(f, values, ("(", ")"), |f, v| write!(f, "{v}"))
+            }
+            Value::Variant {
+                name,
+                variant,
+                value,
+            } => match &**value {
+                Value::Unit => write!(f, "{name}::{variant}"),
+                // Struct variants are already named after the variant
+                Value::Struct { .. } => write!(f, "{name}::{value}"),
+                Value::Seq(values) => {
+                    write!(f, "{name}::{variant}")?;
+                    fmt_list(f, values, ("(", ")"), |f, v| write!(f, "{v}"))
+                }
+                value => write!(f, "{name}::{variant}({value})"),
+            },
+        }
+    }
+}
+
+// *** MsgPayload ***
+
+/// The payload as sent by the remote program - this can either be a string message, a list
+/// of expressions and their values, or a `tracing` span/event
+#[derive(Clone, Debug, PartialEq)]
 pub enum MsgPayload {
     /// A formatted string
     Message(String),
     /// A list of name/value pairs from expressions
     Values(Vec<(String, String)>),
+    /// Sent by rdbg itself: the number of messages the remote program dropped because its queue
+    /// was full (only possible with a non-blocking queue policy)
+    Dropped(u64),
+    /// Sent by rdbg itself: marks the end of replayed history (containing this many messages)
+    /// and the start of live messages
+    HistoryEnd(u64),
+    /// A new `tracing` span was created, optionally as a child of the `parent` span
+    SpanNew {
+        id: u64,
+        parent: Option<u64>,
+        name: String,
+        fields: Vec<(String, String)>,
+    },
+    /// A `tracing` span was entered on the sending thread
+    SpanEnter(u64),
+    /// A `tracing` span was exited on the sending thread
+    SpanExit(u64),
+    /// A `tracing` span was closed. Its ID may be reused by a later span.
+    SpanClose(u64),
+    /// New field values were recorded for a `tracing` span
+    SpanRecord {
+        id: u64,
+        fields: Vec<(String, String)>,
+    },
+    /// A `tracing` event, optionally within a span. The formatted message (if any) is in the
+    /// `message` field.
+    Event {
+        span: Option<u64>,
+        fields: Vec<(String, String)>,
+    },
+    /// The remote program panicked (sent by its panic hook). The location of the panic is the
+    /// filename and line of the [Message].
+    Panic {
+        message: String,
+        /// The name of the thread that panicked (or `<unnamed>`)
+        thread: String,
+        /// The stack of the panicking thread, innermost frame first
+        backtrace: Vec<Frame>,
+    },
+    /// The stack of the sending thread (from `rdbg::backtrace!`), innermost frame first
+    Backtrace(Vec<Frame>),
+    /// How long something took (from `rdbg::time!` or `rdbg::timer!`). The callsite is the
+    /// filename and line of the [Message] and the thread is the one that stopped the timer.
+    Timing {
+        label: String,
+        /// Nanoseconds since the epoch when timing started
+        start_ns: u64,
+        duration: Duration,
+    },
+    /// An amount to add to a named counter (from `rdbg::counter!`)
+    Counter { name: String, delta: i64 },
+    /// The current value of a named gauge (from `rdbg::gauge!`)
+    Gauge { name: String, value: f64 },
+    /// A list of expressions and their serialized values (from `rdbg::svals!`)
+    StructuredValues(Vec<(String, Value)>),
+    /// A labeled binary buffer (from `rdbg::bytes!`)
+    Bytes { label: String, data: Vec<u8> },
+    /// Column headers and rows of cells (from `rdbg::table!`). Rows are not guaranteed to have
+    /// the same number of cells as there are headers.
+    Table {
+        headers: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
 }
 
 impl MsgPayload {
-    fn from_buffer(buffer: &mut ByteBuffer) -> Result<Self, Error> {
+    fn from_buffer(buffer: &mut ByteBuffer, callsite: &Callsite) -> Result<Self, Error> {
         match MsgPayloadVal::from_buffer(buffer)? {
             MsgPayloadVal::Message => {
-                let s = buffer.read_str()?;
+                let s = callsite.text_or(buffer.read_str()?);
                 Ok(MsgPayload::Message(s))
             }
             MsgPayloadVal::Values => {
-                let len = buffer.read_u32()?;
-                // TODO: Do we need to protect against VERY large values here? We will still check
-                // bounds but not before a LOT of memory could be allocated
-                let mut values = Vec::with_capacity(len as usize);
+                let values = buffer.read_row()?;
+                Ok(MsgPayload::Values(callsite.name_values(values)?))
+            }
+            MsgPayloadVal::Dropped => {
+                let count = buffer.read_u64()?;
+                Ok(MsgPayload::Dropped(count))
+            }
+            MsgPayloadVal::HistoryEnd => {
+                let count = buffer.read_u64()?;
+                Ok(MsgPayload::HistoryEnd(count))
+            }
+            MsgPayloadVal::SpanNew => {
+                let id = buffer.read_u64()?;
+                let parent = buffer.read_span_id()?;
+                let name = buffer.read_str()?;
+                let fields = buffer.read_values()?;
+                Ok(MsgPayload::SpanNew {
+                    id,
+                    parent,
+                    name,
+                    fields,
+                })
+            }
+            MsgPayloadVal::SpanEnter => Ok(MsgPayload::SpanEnter(buffer.read_u64()?)),
+            MsgPayloadVal::SpanExit => Ok(MsgPayload::SpanExit(buffer.read_u64()?)),
+            MsgPayloadVal::SpanClose => Ok(MsgPayload::SpanClose(buffer.read_u64()?)),
+            MsgPayloadVal::SpanRecord => {
+                let id = buffer.read_u64()?;
+                let fields = buffer.read_values()?;
+                Ok(MsgPayload::SpanRecord { id, fields })
+            }
+            MsgPayloadVal::Event => {
+                let span = buffer.read_span_id()?;
+                let fields = buffer.read_values()?;
+                Ok(MsgPayload::Event { span, fields })
+            }
+            MsgPayloadVal::Panic => {
+                let message = buffer.read_str()?;
+                let thread = buffer.read_str()?;
+                let backtrace = buffer.read_frames()?;
+                Ok(MsgPayload::Panic {
+                    message,
+                    thread,
+                    backtrace,
+                })
+            }
+            MsgPayloadVal::Backtrace => {
+                let frames = buffer.read_frames()?;
+                Ok(MsgPayload::Backtrace(frames))
+            }
+            MsgPayloadVal::Timing => {
+                let label = callsite.text_or(buffer.read_str()?);
+                let start_ns = buffer.read_u64()?;
+                let duration = Duration::from_nanos(buffer.read_u64()?);
+                Ok(MsgPayload::Timing {
+                    label,
+                    start_ns,
+                    duration,
+                })
+            }
+            MsgPayloadVal::Counter => {
+                let name = callsite.text_or(buffer.read_str()?);
+                let delta = buffer.read_u64()? as i64;
+                Ok(MsgPayload::Counter { name, delta })
+            }
+            MsgPayloadVal::Gauge => {
+                let name = callsite.text_or(buffer.read_str()?);
+                let value = f64::from_bits(buffer.read_u64()?);
+                Ok(MsgPayload::Gauge { name, value })
+            }
+            MsgPayloadVal::StructuredValues => {
+                let values = buffer.read_structured_values()?;
+                Ok(MsgPayload::StructuredValues(callsite.name_values(values)?))
+            }
+            MsgPayloadVal::Bytes => {
+                let label = callsite.text_or(buffer.read_str()?);
+                let data = buffer.read_bytes()?;
+                Ok(MsgPayload::Bytes { label, data })
+            }
+            MsgPayloadVal::Table => {
+                let headers = buffer.read_row()?;
+                let len = buffer.read_len()?;
+                let mut rows = Vec::with_capacity(len);
 
                 for _ in 0..len {
-                    let name = buffer.read_str()?;
-                    let val = buffer.read_str()?;
-                    values.push((name, val));
+                    rows.push(buffer.read_row()?);
                 }
 
-                Ok(MsgPayload::Values(values))
+                Ok(MsgPayload::Table { headers, rows })
             }
         }
     }
 }
 
+// *** RecordKind ***
+
+#[repr(u8)]
+enum RecordKind {
+    Message = 1,
+    Callsite = 2,
+}
+
+impl TryFrom<u8> for RecordKind {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(RecordKind::Message),
+            2 => Ok(RecordKind::Callsite),
+            _ => Err(Error::CorruptMsg),
+        }
+    }
+}
+
+// *** Callsite ***
+
+// Where messages are sent from. The remote program sends each one once per connection (before
+// the first message that refers to it) and messages then only send its ID.
+#[derive(Clone, Debug)]
+struct Callsite {
+    filename: String,
+    line: u32,
+    column: Option<u32>,
+    module_path: Option<String>,
+    // Static text (a message, label or name) that messages leave empty rather than sending
+    text: String,
+    // The names of the expressions whose values messages send
+    names: Vec<String>,
+}
+
+// The callsites received on a connection so far, by ID
+type Callsites = HashMap<u32, Callsite>;
+
+impl Callsite {
+    fn from_buffer(buffer: &mut ByteBuffer) -> Result<(u32, Self), Error> {
+        let id = buffer.read_u32()?;
+        let filename = buffer.read_str()?;
+        let line = buffer.read_u32()?;
+        // Columns start at one, so zero means the column is unknown
+        let column = Some(buffer.read_u32()?).filter(|&column| column != 0);
+        // An empty module path means the module is unknown
+        let module_path = Some(buffer.read_str()?).filter(|path| !path.is_empty());
+        let text = buffer.read_str()?;
+        let names = buffer.read_row()?;
+
+        Ok((
+            id,
+            Self {
+                filename,
+                line,
+                column,
+                module_path,
+                text,
+                names,
+            },
+        ))
+    }
+
+    // Text sent by a message, or this callsite's static text if it was left empty
+    #[inline]
+    fn text_or(&self, text: String) -> String {
+        if text.is_empty() {
+            self.text.clone()
+        } else {
+            text
+        }
+    }
+
+    // Pairs values sent by a message with the names of their expressions
+    fn name_values<T>(&self, values: Vec<T>) -> Result<Vec<(String, T)>, Error> {
+        if values.len() != self.names.len() {
+            return Err(Error::CorruptMsg);
+        }
+
+        Ok(self.names.iter().cloned().zip(values).collect())
+    }
+}
+
 // *** Message ***
 
 /// The primary structure. Represents all the fields of debug information as received from the
 /// debugged program
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Message {
-    /// Milliseconds since epoch at the exact moment the debug message was triggered in the remote program
-    pub time: u64,
+    /// Nanoseconds since epoch at the exact moment the debug message was triggered in the remote program
+    pub time_ns: u64,
+    /// Nanoseconds since the remote program started (see [Hello::start_time_ns]) according to a
+    /// monotonic clock. Unlike `time_ns`, this never goes backwards, so use it to measure intervals.
+    pub mono_ns: u64,
+    /// The order in which messages were triggered in the remote program (across all threads and
+    /// ports), starting from zero. Messages may arrive in a different order than this.
+    pub seq: u64,
     /// The thread ID that invoked the message in the remote program
     pub thread_id: String,
+    /// The name of the thread that invoked the message in the remote program, if it has one
+    pub thread_name: Option<String>,
     /// The filename that invoked the message in the remote program
     pub filename: String,
     /// The line number at which the message was invoked in the remote program
     pub line: u32,
+    /// The column number at which the message was invoked in the remote program, if known (not
+    /// sent for `log` records or `tracing` events)
+    pub column: Option<u32>,
+    /// The module path that invoked the message in the remote program (ex: `my_crate::my_mod`),
+    /// if known (not sent for panics)
+    pub module_path: Option<String>,
+    /// The severity of the message
+    pub level: Level,
+    /// The tag/channel the message was sent on (ex: "db"), if any
+    pub tag: Option<String>,
     /// The message OR expression values sent from the remote program
     pub payload: MsgPayload,
 }
 
 impl Message {
-    fn from_buffer(buffer: &mut ByteBuffer) -> Result<Message, Error> {
-        let time = buffer.read_u64()?;
+    fn from_buffer(buffer: &mut ByteBuffer, callsites: &Callsites) -> Result<Message, Error> {
+        let time_ns = buffer.read_u64()?;
+        let mono_ns = buffer.read_u64()?;
+        let seq = buffer.read_u64()?;
         let thread_id = buffer.read_str()?;
-        let filename = buffer.read_str()?;
-        let line = buffer.read_u32()?;
-        let payload = MsgPayload::from_buffer(buffer)?;
+        // An empty name means the thread is unnamed
+        let thread_name = Some(buffer.read_str()?).filter(|name| !name.is_empty());
+        // A message can only refer to a callsite that was sent before it
+        let callsite = callsites
+            .get(&buffer.read_u32()?)
+            .ok_or(Error::CorruptMsg)?;
+        let level = Level::from_buffer(buffer)?;
+        // An empty tag means the message has no tag
+        let tag = Some(buffer.read_str()?).filter(|tag| !tag.is_empty());
+        let payload = MsgPayload::from_buffer(buffer, callsite)?;
 
         Ok(Self {
-            time,
+            time_ns,
+            mono_ns,
+            seq,
             thread_id,
-            filename,
-            line,
+            thread_name,
+            filename: callsite.filename.clone(),
+            line: callsite.line,
+            column: callsite.column,
+            module_path: callsite.module_path.clone(),
+            level,
+            tag,
             payload,
         })
     }
+
+    /// The name of the crate that invoked the message in the remote program (the first part of
+    /// the module path), if known
+    pub fn crate_name(&self) -> Option<&str> {
+        self.module_path
+            .as_deref()
+            .and_then(|path| path.split("::").next())
+    }
+}
+
+// *** Hello ***
+
+/// Describes the debugged program. It is sent once per connection, right after connecting (see
+/// [Event::Hello]).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Hello {
+    /// The process ID of the debugged program
+    pub pid: u32,
+    /// The path of the debugged program's executable (empty if unknown)
+    pub exe: String,
+    /// The command-line arguments of the debugged program (not including the program name)
+    pub args: Vec<String>,
+    /// The name of the crate sending the messages (ex: "rdbg")
+    pub crate_name: String,
+    /// The version of the crate sending the messages
+    pub crate_version: String,
+    /// Nanoseconds since epoch when the debugged program first used rdbg
+    pub start_time_ns: u64,
+    /// A random ID that is unique to each run of the debugged program. If it is the same as the
+    /// one from a prior connection, the program was only reconnected to rather than restarted.
+    pub session_id: u128,
+}
+
+impl Hello {
+    /// The session ID in standard UUID format (ex: `67e55044-10b1-426f-9247-bb680e5fe0c8`)
+    pub fn session_uuid(&self) -> String {
+        let hex = format!("{:032x}", self.session_id);
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+
+    fn from_buffer(buffer: &mut ByteBuffer) -> Result<Hello, Error> {
+        let pid = buffer.read_u32()?;
+        let exe = buffer.read_str()?;
+        let args = buffer.read_row()?;
+        let crate_name = buffer.read_str()?;
+        let crate_version = buffer.read_str()?;
+        let start_time_ns = buffer.read_u64()?;
+        let session_id = buffer.read_u128()?;
+
+        Ok(Self {
+            pid,
+            exe,
+            args,
+            crate_name,
+            crate_version,
+            start_time_ns,
+            session_id,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::TcpListener;
     use std::thread;
+    use std::time::Duration;
+
+    use crate::{
+        decode_record, ByteBuffer, Callsites, Error, Event, Hello, Level, MsgIterator, MsgListener,
+        DEFAULT_ADDR, LEN_FIELD_SIZE, MAX_LEN, MAX_VALUE_DEPTH,
+    };
+
+    fn current_thread_id() -> String {
+        // rdbg only sends the number portion of the thread ID
+        let thread_id = format!("{:?}", thread::current().id());
+        thread_id
+            .trim_start_matches("ThreadId(")
+            .trim_end_matches(')')
+            .to_string()
+    }
+
+    // Decodes a message the same way as if it were the first one received on a connection
+    fn decode(raw_msg: &rdbg::Message) -> crate::Message {
+        let mut callsites = Callsites::new();
+        let records = [raw_msg.callsite_slice().unwrap(), raw_msg.as_slice()];
+
+        records
+            .iter()
+            .find_map(|record| {
+                let mut buffer = ByteBuffer::from_vec(record[LEN_FIELD_SIZE..].to_vec());
+                decode_record(&mut buffer, &mut callsites).expect("Corrupt message")
+            })
+            .expect("Expected a message")
+    }
+
+    fn assert_connected(viewer: &mut MsgIterator) -> Hello {
+        assert!(matches!(viewer.next(), Some(Ok(Event::Connected(_)))));
+        match viewer.next() {
+            Some(Ok(Event::Hello(hello))) => hello,
+            _ => panic!("Expected a hello"),
+        }
+    }
+
+    // Opens a port the OS just handed out (so it can't clash with other tests) and connects a
+    // viewer to it
+    fn connect() -> (rdbg::RemoteDebug, MsgIterator) {
+        let port = TcpListener::bind((DEFAULT_ADDR, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let debug = rdbg::port(port);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, port).unwrap();
+        assert_connected(&mut viewer);
+        (debug, viewer)
+    }
 
-    use crate::{ByteBuffer, LEN_FIELD_SIZE};
+    fn next_payload(viewer: &mut MsgIterator) -> crate::MsgPayload {
+        match viewer.next() {
+            Some(Ok(Event::Message(msg))) => msg.payload,
+            _ => panic!("Expected a message"),
+        }
+    }
 
     #[test]
     fn deserialize_msg() {
@@ -191,37 +1042,65 @@ mod tests {
         let line: u32 = line!();
         let message = "message".to_string();
 
-        let raw_msg =
-            rdbg::Message::new(filename, line, rdbg::MsgPayload::Message(message.clone()));
+        let raw_msg = rdbg::Message::new(
+            rdbg::Callsite::dynamic(filename, line, 9, module_path!()),
+            rdbg::Level::Warn,
+            Some("tag"),
+            rdbg::MsgPayload::Message(message.clone().into()),
+        );
 
         let expected_msg = crate::Message {
-            time: 42,
-            thread_id: format!("{:?}", thread::current().id()),
+            time_ns: 42,
+            mono_ns: 42,
+            seq: 42,
+            thread_id: current_thread_id(),
+            thread_name: thread::current().name().map(str::to_string),
             filename: filename.to_string(),
             line,
+            column: Some(9),
+            module_path: Some(module_path!().to_string()),
+            level: Level::Warn,
+            tag: Some("tag".to_string()),
             payload: crate::MsgPayload::Message(message),
         };
-        let mut buffer = ByteBuffer::from_vec(raw_msg.as_slice()[LEN_FIELD_SIZE..].to_vec());
-        let mut actual_msg = crate::Message::from_buffer(&mut buffer).expect("Corrupt message");
+        let mut actual_msg = decode(&raw_msg);
 
-        // Cheat on time since we have no way to know exact time
-        actual_msg.time = expected_msg.time;
+        // Cheat on time and sequence # since we have no way to know them exactly
+        assert!(actual_msg.time_ns > 0);
+        actual_msg.time_ns = expected_msg.time_ns;
+        actual_msg.mono_ns = expected_msg.mono_ns;
+        actual_msg.seq = expected_msg.seq;
         assert_eq!(expected_msg, actual_msg);
     }
 
     #[test]
     fn deserialize_vals() {
+        const LINE: u32 = line!();
+        static CALLSITE: rdbg::Callsite =
+            rdbg::Callsite::new(file!(), LINE, 0, "", &["name1", "name2"]);
         let filename = file!();
-        let line: u32 = line!();
+        let line = LINE;
         let values = vec![("name1", "val1".to_string()), ("name2", "val2".to_string())];
 
-        let raw_msg = rdbg::Message::new(filename, line, rdbg::MsgPayload::Values(values.clone()));
+        let raw_msg = rdbg::Message::new(
+            &CALLSITE,
+            rdbg::Level::Debug,
+            None,
+            rdbg::MsgPayload::Values(values.iter().map(|(_, v)| v.clone()).collect()),
+        );
 
         let expected_msg = crate::Message {
-            time: 42,
-            thread_id: format!("{:?}", thread::current().id()),
+            time_ns: 42,
+            mono_ns: 42,
+            seq: 42,
+            thread_id: current_thread_id(),
+            thread_name: thread::current().name().map(str::to_string),
             filename: filename.to_string(),
             line,
+            column: None,
+            module_path: None,
+            level: Level::Debug,
+            tag: None,
             payload: crate::MsgPayload::Values(
                 values
                     .into_iter()
@@ -229,54 +1108,1047 @@ mod tests {
                     .collect(),
             ),
         };
-        let mut buffer = ByteBuffer::from_vec(raw_msg.as_slice()[LEN_FIELD_SIZE..].to_vec());
-        let mut actual_msg = crate::Message::from_buffer(&mut buffer).expect("Corrupt message");
+        let mut actual_msg = decode(&raw_msg);
 
-        // Cheat on time since we have no way to know exact time
-        actual_msg.time = expected_msg.time;
+        // Cheat on time and sequence # since we have no way to know them exactly
+        assert!(actual_msg.time_ns > 0);
+        actual_msg.time_ns = expected_msg.time_ns;
+        actual_msg.mono_ns = expected_msg.mono_ns;
+        actual_msg.seq = expected_msg.seq;
         assert_eq!(expected_msg, actual_msg);
     }
-}
 
-// *** Error ***
+    #[test]
+    fn thread_names() {
+        let read_thread_name = || {
+            let raw_msg = rdbg::Message::new(
+                rdbg::Callsite::dynamic("", 0, 0, ""),
+                rdbg::Level::Info,
+                None,
+                rdbg::MsgPayload::Message(String::new().into()),
+            );
+            decode(&raw_msg).thread_name
+        };
 
-/// Errors that can occur based on data received from the debugged program
-pub enum Error {
-    /// The remote debugged program is using a different version of rdbg that is incompatible
-    BadVersion,
-    /// A string in the [Message] was not valid UTF8
-    BadUtf8(Utf8Error),
-    /// The binary payload of the [Message] was corrupted and could not be decoded
-    CorruptMsg,
-}
+        let named = thread::Builder::new()
+            .name("worker-1".to_string())
+            .spawn(read_thread_name)
+            .unwrap();
+        assert_eq!(Some("worker-1".to_string()), named.join().unwrap());
 
-impl Debug for Error {
-    #[inline]
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        <Self as Display>::fmt(self, f)
+        let unnamed = thread::spawn(read_thread_name);
+        assert_eq!(None, unnamed.join().unwrap());
     }
-}
 
-impl Display for Error {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Error::BadVersion => f.write_str("This library only supports protocol version 1"),
-            Error::BadUtf8(err) => std::fmt::Display::fmt(err, f),
-            Error::CorruptMsg => f.write_str("The message payload was corrupted"),
+    #[test]
+    fn broadcast_to_multiple_viewers() {
+        const PORT: u16 = 13601;
+        let debug = rdbg::port(PORT);
+
+        let mut viewers = [
+            MsgIterator::new(DEFAULT_ADDR, PORT).unwrap(),
+            MsgIterator::new(DEFAULT_ADDR, PORT).unwrap(),
+        ];
+        for viewer in &mut viewers {
+            assert_connected(viewer);
         }
-    }
-}
 
-impl std::error::Error for Error {}
+        rdbg::msg!(&debug, ["broadcast"]);
+        debug.flush();
 
-// *** Event ***
+        for viewer in &mut viewers {
+            match viewer.next() {
+                Some(Ok(Event::Message(msg))) => {
+                    assert_eq!(
+                        crate::MsgPayload::Message("broadcast".to_string()),
+                        msg.payload
+                    )
+                }
+                _ => panic!("Expected a message"),
+            }
+        }
+    }
+
+    #[test]
+    fn stalled_viewer() {
+        use std::io::Read;
+        use std::net::TcpStream;
+        use std::sync::mpsc;
+        use std::time::Instant;
+
+        const PORT: u16 = 13619;
+        let debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        // Once this has the version it is registered, but it never reads anything else
+        let mut stalled = TcpStream::connect((DEFAULT_ADDR, PORT)).unwrap();
+        stalled.read_exact(&mut [0]).unwrap();
+
+        // Keep the other viewer reading so only the stalled one falls behind
+        let reader = thread::spawn(move || {
+            viewer
+                .map_while(|event| match event {
+                    Ok(Event::Message(msg)) => Some(msg.payload),
+                    _ => None,
+                })
+                .take_while(|payload| *payload != crate::MsgPayload::Message("done".to_string()))
+                .count()
+        });
+
+        // Enough to fill the socket buffers so the stalled viewer's writer blocks
+        for _ in 0..64 {
+            rdbg::bytes!(&debug, ["big", vec![0u8; 1 << 20]]);
+        }
+        rdbg::msg!(&debug, ["done"]);
+
+        let (sender, receiver) = mpsc::channel();
+        let start = Instant::now();
+        thread::spawn(move || {
+            debug.flush();
+            let _ = sender.send(());
+        });
+
+        // The stalled viewer is disconnected instead of holding up the flush forever
+        assert!(receiver.recv_timeout(Duration::from_secs(10)).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(64, reader.join().unwrap());
+    }
+
+    #[test]
+    fn independent_ports() {
+        const PORTS: [u16; 2] = [13602, 13603];
+        let debugs = PORTS.map(rdbg::port);
+        let mut viewers = PORTS.map(|port| MsgIterator::new(DEFAULT_ADDR, port).unwrap());
+
+        for viewer in &mut viewers {
+            assert_connected(viewer);
+        }
+
+        for (debug, port) in debugs.iter().zip(PORTS) {
+            rdbg::msg!(debug, ["port {port}"]);
+            debug.flush();
+        }
+
+        for (viewer, port) in viewers.iter_mut().zip(PORTS) {
+            match viewer.next() {
+                Some(Ok(Event::Message(msg))) => {
+                    assert_eq!(
+                        crate::MsgPayload::Message(format!("port {port}")),
+                        msg.payload
+                    )
+                }
+                _ => panic!("Expected a message"),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() {
+        let path = crate::socket_path(&format!("unix-socket-test-{}", std::process::id()));
+        let debug = rdbg::unix(&path);
+        let mut viewer = MsgIterator::unix(&path);
+
+        assert_connected(&mut viewer);
+        rdbg::msg!(&debug, ["unix"]);
+        debug.flush();
+
+        match viewer.next() {
+            Some(Ok(Event::Message(msg))) => {
+                assert_eq!(crate::MsgPayload::Message("unix".to_string()), msg.payload)
+            }
+            _ => panic!("Expected a message"),
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn listen_for_programs() {
+        let mut listener = MsgListener::bind(DEFAULT_ADDR, 0).unwrap();
+        let debug = rdbg::connect(&listener.addr().to_string());
+
+        rdbg::msg!(&debug, ["dial out"]);
+        debug.flush();
+
+        let (source, event) = listener.next().unwrap();
+        assert!(matches!(event, Ok(Event::Connected(_))));
+        let (_, event) = listener.next().unwrap();
+        assert!(matches!(event, Ok(Event::Hello(_))));
+
+        match listener.next() {
+            Some((msg_source, Ok(Event::Message(msg)))) => {
+                assert_eq!(source, msg_source);
+                assert_eq!(
+                    crate::MsgPayload::Message("dial out".to_string()),
+                    msg.payload
+                )
+            }
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    #[test]
+    fn replay_history() {
+        const PORT: u16 = 13604;
+        let debug = rdbg::port(PORT);
+        debug.set_history(rdbg::History::Messages(1));
+
+        rdbg::msg!(&debug, ["dropped from history"]);
+        rdbg::msg!(&debug, ["history"]);
+
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+        rdbg::msg!(&debug, ["live"]);
+
+        let mut next_payload = || match viewer.next() {
+            Some(Ok(Event::Message(msg))) => msg.payload,
+            _ => panic!("Expected a message"),
+        };
+        assert_eq!(
+            crate::MsgPayload::Message("history".to_string()),
+            next_payload()
+        );
+        assert_eq!(crate::MsgPayload::HistoryEnd(1), next_payload());
+        assert_eq!(
+            crate::MsgPayload::Message("live".to_string()),
+            next_payload()
+        );
+    }
+
+    #[test]
+    fn tagged_messages() {
+        const PORT: u16 = 13605;
+        let debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        {
+            let _tag = rdbg::scoped_tag("scoped");
+            rdbg::msg!(&debug, ["scoped"]);
+            rdbg::msg!(debug.with_tag("explicit"), ["explicit"]);
+        }
+        rdbg::msg!(&debug, ["untagged"]);
+
+        let mut next_tag = || match viewer.next() {
+            Some(Ok(Event::Message(msg))) => msg.tag,
+            _ => panic!("Expected a message"),
+        };
+        assert_eq!(Some("scoped".to_string()), next_tag());
+        assert_eq!(Some("explicit".to_string()), next_tag());
+        assert_eq!(None, next_tag());
+    }
+
+    #[test]
+    fn log_records() {
+        const PORT: u16 = 13606;
+        rdbg::log::Logger::new(rdbg::port(PORT))
+            .init(log::LevelFilter::Info)
+            .unwrap();
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        log::debug!("filtered out");
+        let line = line!() + 1;
+        log::warn!(target: "db", "Hello {}", "log");
+
+        match viewer.next() {
+            Some(Ok(Event::Message(msg))) => {
+                assert_eq!(file!(), msg.filename);
+                assert_eq!(line, msg.line);
+                assert_eq!(Level::Warn, msg.level);
+                assert_eq!(Some("db".to_string()), msg.tag);
+                assert_eq!(
+                    crate::MsgPayload::Message("Hello log".to_string()),
+                    msg.payload
+                );
+            }
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    #[test]
+    fn tracing_spans() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        const PORT: u16 = 13607;
+        let subscriber =
+            tracing_subscriber::registry().with(rdbg::tracing::Layer::new(rdbg::port(PORT)));
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("outer", id = 1);
+            let _outer = outer.enter();
+            tracing::info_span!("inner").in_scope(|| tracing::info!(answer = 42, "hello"));
+        });
+
+        let payloads: Vec<_> = (0..9)
+            .map(|_| match viewer.next() {
+                Some(Ok(Event::Message(msg))) => msg.payload,
+                _ => panic!("Expected a message"),
+            })
+            .collect();
+
+        let (outer, inner) = match (&payloads[0], &payloads[2]) {
+            (
+                crate::MsgPayload::SpanNew {
+                    id: outer,
+                    parent: None,
+                    name: outer_name,
+                    fields,
+                },
+                crate::MsgPayload::SpanNew {
+                    id: inner,
+                    parent: Some(parent),
+                    name: inner_name,
+                    ..
+                },
+            ) if outer_name == "outer" && inner_name == "inner" && parent == outer => {
+                assert_eq!(&vec![("id".to_string(), "1".to_string())], fields);
+                (*outer, *inner)
+            }
+            _ => panic!("Expected outer and inner spans, got: {payloads:?}"),
+        };
+
+        assert_eq!(crate::MsgPayload::SpanEnter(outer), payloads[1]);
+        assert_eq!(crate::MsgPayload::SpanEnter(inner), payloads[3]);
+        match &payloads[4] {
+            crate::MsgPayload::Event { span, fields } => {
+                assert_eq!(Some(inner), *span);
+                assert!(fields.contains(&("message".to_string(), "hello".to_string())));
+                assert!(fields.contains(&("answer".to_string(), "42".to_string())));
+            }
+            payload => panic!("Expected an event, got: {payload:?}"),
+        }
+        assert_eq!(crate::MsgPayload::SpanExit(inner), payloads[5]);
+        assert_eq!(crate::MsgPayload::SpanClose(inner), payloads[6]);
+        assert_eq!(crate::MsgPayload::SpanExit(outer), payloads[7]);
+        assert_eq!(crate::MsgPayload::SpanClose(outer), payloads[8]);
+    }
+
+    #[test]
+    fn panic_hook() {
+        const PORT: u16 = 13608;
+        let debug = rdbg::port(PORT);
+        // The hook is process wide, so put back whatever was there before once we are done
+        let prev = std::panic::take_hook();
+        debug.install_panic_hook();
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        let line = line!() + 3;
+        let result = thread::Builder::new()
+            .name("panicker".to_string())
+            .spawn(|| panic!("boom"))
+            .unwrap()
+            .join();
+        std::panic::set_hook(prev);
+        assert!(result.is_err());
+
+        match viewer.next() {
+            Some(Ok(Event::Message(msg))) => {
+                assert_eq!(file!(), msg.filename);
+                assert_eq!(line, msg.line);
+                assert_eq!(Level::Error, msg.level);
+                match msg.payload {
+                    crate::MsgPayload::Panic {
+                        message,
+                        thread,
+                        backtrace,
+                    } => {
+                        assert_eq!("boom", message);
+                        assert_eq!("panicker", thread);
+                        assert!(backtrace
+                            .iter()
+                            .any(|frame| frame.function.contains("tests::panic_hook")));
+                    }
+                    payload => panic!("Expected a panic, got: {payload:?}"),
+                }
+            }
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    #[test]
+    fn backtrace_frames() {
+        const PORT: u16 = 13609;
+        let debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        let line = line!() + 1;
+        rdbg::backtrace!(&debug);
+
+        match viewer.next() {
+            Some(Ok(Event::Message(msg))) => match msg.payload {
+                crate::MsgPayload::Backtrace(frames) => {
+                    // The innermost frame should be this test, not rdbg itself
+                    let frame = &frames[0];
+                    assert!(frame.function.ends_with("tests::backtrace_frames"));
+                    assert!(frame.file.as_ref().unwrap().ends_with("src/lib.rs"));
+                    assert_eq!(Some(line), frame.line);
+                }
+                payload => panic!("Expected a backtrace, got: {payload:?}"),
+            },
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    #[test]
+    fn timing() {
+        const PORT: u16 = 13610;
+        let debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        let value = rdbg::time!(
+            &debug,
+            ["sleep", {
+                thread::sleep(Duration::from_millis(10));
+                42
+            }]
+        );
+        assert_eq!(42, value);
+
+        match viewer.next() {
+            Some(Ok(Event::Message(msg))) => match msg.payload {
+                crate::MsgPayload::Timing {
+                    label,
+                    start_ns,
+                    duration,
+                } => {
+                    assert_eq!("sleep", label);
+                    assert!(start_ns > 0 && start_ns <= msg.time_ns);
+                    assert!(duration >= Duration::from_millis(10));
+                }
+                payload => panic!("Expected a timing, got: {payload:?}"),
+            },
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    #[test]
+    fn metrics() {
+        const PORT: u16 = 13611;
+        let debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        rdbg::counter!(&debug, ["requests", -3]);
+        rdbg::counter!(&debug, ["bytes", u32::MAX]);
+        rdbg::gauge!(&debug, ["load", 0.5f32]);
+        rdbg::gauge!(&debug, ["queue_len", 12]);
+
+        let mut next_payload = || match viewer.next() {
+            Some(Ok(Event::Message(msg))) => msg.payload,
+            _ => panic!("Expected a message"),
+        };
+        assert_eq!(
+            crate::MsgPayload::Counter {
+                name: "requests".to_string(),
+                delta: -3
+            },
+            next_payload()
+        );
+        assert_eq!(
+            crate::MsgPayload::Counter {
+                name: "bytes".to_string(),
+                delta: u32::MAX.into()
+            },
+            next_payload()
+        );
+        assert_eq!(
+            crate::MsgPayload::Gauge {
+                name: "load".to_string(),
+                value: 0.5
+            },
+            next_payload()
+        );
+        assert_eq!(
+            crate::MsgPayload::Gauge {
+                name: "queue_len".to_string(),
+                value: 12.0
+            },
+            next_payload()
+        );
+    }
+
+    #[test]
+    fn structured_values() {
+        use std::collections::BTreeMap;
+
+        use crate::Value;
+
+        #[derive(serde::Serialize)]
+        struct Point {
+            x: i32,
+            y: f64,
+        }
+
+        #[derive(serde::Serialize)]
+        enum Shape {
+            Empty,
+            Circle { center: Point, radius: u8 },
+            Line(Point, Point),
+        }
+
+        const PORT: u16 = 13612;
+        let debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        let circle = Shape::Circle {
+            center: Point { x: -1, y: 2.5 },
+            radius: 3,
+        };
+        let shapes = vec![
+            Shape::Empty,
+            Shape::Line(Point { x: 0, y: 0.0 }, Point { x: 1, y: 1.0 }),
+        ];
+        let names = BTreeMap::from([("a", Some('x')), ("b", None)]);
+        rdbg::svals!(&debug, [circle, shapes, names]);
+
+        let values = match viewer.next() {
+            Some(Ok(Event::Message(msg))) => match msg.payload {
+                crate::MsgPayload::StructuredValues(values) => values,
+                payload => panic!("Expected structured values: {payload:?}"),
+            },
+            _ => panic!("Expected a message"),
+        };
+
+        let point = |x, y| Value::Struct {
+            name: "Point".to_string(),
+            fields: vec![
+                ("x".to_string(), Value::I64(x)),
+                ("y".to_string(), Value::F64(y)),
+            ],
+        };
+        let variant = |variant: &str, value| Value::Variant {
+            name: "Shape".to_string(),
+            variant: variant.to_string(),
+            value: Box::new(value),
+        };
+        assert_eq!(
+            vec![
+                (
+                    "circle".to_string(),
+                    variant(
+                        "Circle",
+                        Value::Struct {
+                            name: "Circle".to_string(),
+                            fields: vec![
+                                ("center".to_string(), point(-1, 2.5)),
+                                ("radius".to_string(), Value::U64(3)),
+                            ],
+                        }
+                    )
+                ),
+                (
+                    "shapes".to_string(),
+                    Value::Seq(vec![
+                        variant("Empty", Value::Unit),
+                        variant("Line", Value::Seq(vec![point(0, 0.0), point(1, 1.0)])),
+                    ])
+                ),
+                (
+                    "names".to_string(),
+                    Value::Map(vec![
+                        (
+                            Value::Str("a".to_string()),
+                            Value::Option(Some(Box::new(Value::Char('x'))))
+                        ),
+                        (Value::Str("b".to_string()), Value::Option(None)),
+                    ])
+                ),
+            ],
+            values
+        );
+
+        // Navigation and formatting
+        let circle = &values[0].1;
+        assert_eq!(
+            Some(&Value::F64(2.5)),
+            circle.get("center").and_then(|center| center.get("y"))
+        );
+        assert_eq!(Some(&Value::U64(3)), circle.get("radius"));
+        assert_eq!(
+            Some(&point(1, 1.0)),
+            values[1].1.index(1).and_then(|line| line.index(1))
+        );
+        assert_eq!(
+            "Shape::Circle { center: Point { x: -1, y: 2.5 }, radius: 3 }",
+            circle.to_string()
+        );
+        assert_eq!(
+            "[Shape::Empty, Shape::Line(Point { x: 0, y: 0.0 }, Point { x: 1, y: 1.0 })]",
+            values[1].1.to_string()
+        );
+        assert_eq!(r#"{"a": Some('x'), "b": None}"#, values[2].1.to_string());
+    }
+
+    #[test]
+    fn deep_values() {
+        use crate::Value;
+
+        #[derive(serde::Serialize)]
+        enum Chain {
+            End,
+            Link(Box<Chain>),
+        }
+
+        #[derive(serde::Serialize)]
+        struct Nested(Vec<Nested>);
+
+        const PORT: u16 = 13620;
+        let debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        let chain = (0..500).fold(Chain::End, |chain, _| Chain::Link(Box::new(chain)));
+        let nested = (0..500).fold(Nested(Vec::new()), |nested, _| Nested(vec![nested]));
+        rdbg::svals!(&debug, [chain, nested]);
+        rdbg::msg!(&debug, ["still connected"]);
+
+        // Too deep values are cut off with a marker rather than rejected by the viewer
+        let values = match viewer.next() {
+            Some(Ok(Event::Message(msg))) => match msg.payload {
+                crate::MsgPayload::StructuredValues(values) => values,
+                payload => panic!("Expected structured values: {payload:?}"),
+            },
+            _ => panic!("Expected a message"),
+        };
+        for (_, value) in &values {
+            let (mut value, mut depth) = (value, 0);
+            loop {
+                value = match value {
+                    Value::Variant { value, .. } => value,
+                    Value::Seq(values) => &values[0],
+                    _ => break,
+                };
+                depth += 1;
+            }
+            assert_eq!(&Value::Str("<max depth exceeded>".to_string()), value);
+            assert!(depth <= MAX_VALUE_DEPTH);
+        }
+
+        match viewer.next() {
+            Some(Ok(Event::Message(msg))) => assert_eq!(
+                crate::MsgPayload::Message("still connected".to_string()),
+                msg.payload
+            ),
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    #[test]
+    fn huge_lengths() {
+        // A corrupt count is rejected before room is allocated for that many items
+        let mut buffer = ByteBuffer::from_vec(u32::MAX.to_be_bytes().to_vec());
+        assert!(matches!(buffer.read_row(), Err(Error::CorruptMsg)));
+
+        let len = MAX_LEN as u32;
+        let mut buffer =
+            ByteBuffer::from_vec([len.to_be_bytes(), (len + 1).to_be_bytes()].concat());
+        assert_eq!(MAX_LEN, buffer.read_len().unwrap());
+        assert!(matches!(buffer.read_len(), Err(Error::CorruptMsg)));
+    }
+
+    #[test]
+    fn binary_data() {
+        let (debug, mut viewer) = connect();
+
+        let buf = vec![0u8, 1, 0xfe, 0xff, b'a'];
+        rdbg::bytes!(&debug, ["packet", &buf]);
+        rdbg::bytes!(&debug, ["empty", b""]);
+
+        assert_eq!(
+            crate::MsgPayload::Bytes {
+                label: "packet".to_string(),
+                data: buf
+            },
+            next_payload(&mut viewer)
+        );
+        assert_eq!(
+            crate::MsgPayload::Bytes {
+                label: "empty".to_string(),
+                data: vec![]
+            },
+            next_payload(&mut viewer)
+        );
+    }
+
+    #[test]
+    fn table() {
+        const PORT: u16 = 13614;
+        let debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        let people = [("Alice", 32), ("Bob", 7)];
+        rdbg::table!(
+            &debug,
+            [
+                ["Name", "Age"],
+                people
+                    .iter()
+                    .map(|(name, age)| [name.to_string(), age.to_string()])
+            ]
+        );
+
+        let payload = match viewer.next() {
+            Some(Ok(Event::Message(msg))) => msg.payload,
+            _ => panic!("Expected a message"),
+        };
+        let row = |cells: &[&str]| cells.iter().map(|cell| cell.to_string()).collect();
+        assert_eq!(
+            crate::MsgPayload::Table {
+                headers: row(&["Name", "Age"]),
+                rows: vec![row(&["Alice", "32"]), row(&["Bob", "7"])],
+            },
+            payload
+        );
+    }
+
+    #[test]
+    fn hello() {
+        const PORT: u16 = 13615;
+        let _debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        let hello = assert_connected(&mut viewer);
+
+        assert_eq!(std::process::id(), hello.pid);
+        assert!(hello.exe.contains("rdbg_client"));
+        assert_eq!(std::env::args().skip(1).collect::<Vec<_>>(), hello.args);
+        assert_eq!("rdbg", hello.crate_name);
+        assert!(!hello.crate_version.is_empty());
+        assert!(hello.start_time_ns > 0);
+        // Version 4 UUID
+        assert_eq!(b'4', hello.session_uuid().as_bytes()[14]);
+
+        // Another viewer of the same run sees the same session
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_eq!(hello, assert_connected(&mut viewer));
+    }
+
+    #[test]
+    fn message_order() {
+        const PORT: u16 = 13616;
+        let debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        let hello = assert_connected(&mut viewer);
+
+        for idx in 0..3 {
+            rdbg::msg!(&debug, ["{idx}"]);
+        }
+
+        let mut messages = Vec::new();
+        for _ in 0..3 {
+            match viewer.next() {
+                Some(Ok(Event::Message(msg))) => messages.push(msg),
+                _ => panic!("Expected a message"),
+            }
+        }
+
+        for pair in messages.windows(2) {
+            // Other tests may send messages in between, so we can't expect consecutive numbers
+            assert!(pair[0].seq < pair[1].seq);
+            assert!(pair[0].mono_ns <= pair[1].mono_ns);
+        }
+        assert!(messages[0].time_ns >= hello.start_time_ns);
+    }
+
+    #[test]
+    fn callsites() {
+        const PORT: u16 = 13617;
+        let debug = rdbg::port(PORT);
+        let mut viewer = MsgIterator::new(DEFAULT_ADDR, PORT).unwrap();
+        assert_connected(&mut viewer);
+
+        // Two calls on the same line can only be told apart by column
+        #[rustfmt::skip]
+        fn send(d: &rdbg::RemoteDebug) -> u32 { rdbg::msg!(d, ["a"]); rdbg::msg!(d, ["b"]); line!() }
+        let line = send(&debug);
+
+        let mut columns = Vec::new();
+        for _ in 0..2 {
+            match viewer.next() {
+                Some(Ok(Event::Message(msg))) => {
+                    assert_eq!(line, msg.line);
+                    assert_eq!(Some(module_path!()), msg.module_path.as_deref());
+                    assert_eq!(Some("rdbg_client"), msg.crate_name());
+                    columns.push(msg.column.unwrap());
+                }
+                _ => panic!("Expected a message"),
+            }
+        }
+        assert!(columns[0] < columns[1]);
+    }
+
+    #[test]
+    fn callsite_interning() {
+        const PORT: u16 = 13618;
+        let debug = rdbg::port(PORT);
+        let addr = crate::Addr::Tcp(format!("{DEFAULT_ADDR}:{PORT}").parse().unwrap());
+        let mut buffer = ByteBuffer::new(crate::BUFFER_SIZE);
+
+        // Read the raw records so we can see which ones were sent
+        let mut stream = loop {
+            if let Ok(mut stream) = crate::Stream::connect(&addr) {
+                if crate::read_version(&mut stream, &mut buffer).unwrap() {
+                    break stream;
+                }
+            }
+            thread::sleep(Duration::from_millis(crate::CONNECT_WAIT_TIME));
+        };
+        crate::read_hello(&mut stream, &mut buffer)
+            .unwrap()
+            .unwrap();
+
+        for _ in 0..3 {
+            rdbg::msg!(&debug, ["interned"]);
+        }
+
+        let mut callsites = Callsites::new();
+        let mut kinds = Vec::new();
+        while kinds.iter().filter(|&&kind| kind == "message").count() < 3 {
+            let msg = crate::read_record(&mut stream, &mut buffer, |buffer| {
+                decode_record(buffer, &mut callsites)
+            })
+            .unwrap()
+            .unwrap();
+
+            kinds.push(match msg {
+                Some(msg) => {
+                    assert_eq!(file!(), msg.filename);
+                    assert_eq!(
+                        crate::MsgPayload::Message("interned".to_string()),
+                        msg.payload
+                    );
+                    "message"
+                }
+                None => "callsite",
+            });
+        }
+
+        // The callsite is only sent before the first message that uses it, and carries its text
+        assert_eq!(vec!["callsite", "message", "message", "message"], kinds);
+        assert_eq!(1, callsites.len());
+        assert!(callsites
+            .values()
+            .all(|callsite| callsite.text == "interned"));
+    }
+}
+
+// *** Error ***
+
+/// Errors that can occur based on data received from the debugged program
+pub enum Error {
+    /// The remote debugged program is using a different version of rdbg that is incompatible
+    BadVersion,
+    /// A string in the [Message] was not valid UTF8
+    BadUtf8(Utf8Error),
+    /// The binary payload of the [Message] was corrupted and could not be decoded
+    CorruptMsg,
+}
+
+impl Debug for Error {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        <Self as Display>::fmt(self, f)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::BadVersion => write!(
+                f,
+                "This library only supports protocol version {WIRE_PROTOCOL_VERSION}"
+            ),
+            Error::BadUtf8(err) => std::fmt::Display::fmt(err, f),
+            Error::CorruptMsg => f.write_str("The message payload was corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// *** Addr ***
+
+/// The address of the debugged program
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Addr {
+    /// A TCP socket address
+    Tcp(SocketAddr),
+    /// A Unix domain socket path
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Display for Addr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Addr::Tcp(addr) => Display::fmt(addr, f),
+            #[cfg(unix)]
+            Addr::Unix(path) => Display::fmt(&path.display(), f),
+        }
+    }
+}
+
+/// Returns a Unix domain socket path in the temp directory based on `name`. This is the same
+/// path the debugged program will listen on when using `rdbg::socket_path` with the same `name`.
+#[cfg(unix)]
+pub fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rdbg-{name}.sock"))
+}
+
+// *** Stream ***
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn connect(addr: &Addr) -> io::Result<Self> {
+        match addr {
+            Addr::Tcp(addr) => Ok(Stream::Tcp(TcpStream::connect(addr)?)),
+            #[cfg(unix)]
+            Addr::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+// Checks a length read off the wire before anything is allocated based on it
+#[inline]
+fn check_len(len: u32) -> Result<usize, Error> {
+    match len as usize {
+        len if len <= MAX_LEN => Ok(len),
+        _ => Err(Error::CorruptMsg),
+    }
+}
+
+// Reads a length prefixed record (a message or hello) and decodes it with `from_buffer`.
+// The outer error means the connection was lost, the inner error means the record was bad
+fn read_record<T>(
+    stream: &mut Stream,
+    buffer: &mut ByteBuffer,
+    from_buffer: impl FnOnce(&mut ByteBuffer) -> Result<T, Error>,
+) -> io::Result<Result<T, Error>> {
+    buffer.read_from_stream(stream, LEN_FIELD_SIZE)?;
+    // We know this is long enough - guaranteed by read above
+    let len = match check_len(buffer.read_u32().unwrap()) {
+        Ok(len) => len,
+        Err(err) => return Ok(Err(err)),
+    };
+
+    match len.checked_sub(LEN_FIELD_SIZE) {
+        Some(len) => {
+            buffer.read_from_stream(stream, len)?;
+            Ok(from_buffer(buffer))
+        }
+        None => Ok(Err(Error::CorruptMsg)),
+    }
+}
+
+// Reads records until the next message, keeping track of any callsites received along the way
+fn read_message(
+    stream: &mut Stream,
+    buffer: &mut ByteBuffer,
+    callsites: &mut Callsites,
+) -> io::Result<Result<Message, Error>> {
+    loop {
+        match read_record(stream, buffer, |buffer| decode_record(buffer, callsites))? {
+            Ok(Some(msg)) => return Ok(Ok(msg)),
+            Ok(None) => {}
+            Err(err) => return Ok(Err(err)),
+        }
+    }
+}
+
+// Decodes a record sent after the hello, returning the message if it was one
+fn decode_record(
+    buffer: &mut ByteBuffer,
+    callsites: &mut Callsites,
+) -> Result<Option<Message>, Error> {
+    match RecordKind::try_from(buffer.read_u8()?)? {
+        RecordKind::Message => Message::from_buffer(buffer, callsites).map(Some),
+        RecordKind::Callsite => {
+            let (id, callsite) = Callsite::from_buffer(buffer)?;
+            callsites.insert(id, callsite);
+            Ok(None)
+        }
+    }
+}
+
+#[inline]
+fn read_hello(stream: &mut Stream, buffer: &mut ByteBuffer) -> io::Result<Result<Hello, Error>> {
+    read_record(stream, buffer, Hello::from_buffer)
+}
+
+fn read_version(stream: &mut Stream, buffer: &mut ByteBuffer) -> io::Result<bool> {
+    buffer.read_from_stream(stream, size_of::<u8>())?;
+    // We know this is long enough - guaranteed by read above
+    Ok(buffer.read_u8().unwrap() == WIRE_PROTOCOL_VERSION)
+}
+
+// *** Listener ***
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<(Stream, Addr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                Ok((Stream::Tcp(stream), Addr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, path) => {
+                // Connecting sockets are usually unnamed, so just use our own path
+                let (stream, _) = listener.accept()?;
+                Ok((Stream::Unix(stream), Addr::Unix(path.clone())))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    // Only remove sockets - anything else at this path is likely a mistake and binding will fail
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+// *** Event ***
 
 /// This represents various events that occur during iteration and are returned by [MsgIterator]
+/// and [MsgListener]
 pub enum Event {
     /// Returned when attached to debugged program
-    Connected(SocketAddr),
+    Connected(Addr),
+    /// Returned right after [Event::Connected] with a description of the debugged program
+    Hello(Hello),
     /// Returned when loses connection to debugged program
-    Disconnected(SocketAddr),
+    Disconnected(Addr),
     /// Returned when a new message from the debugged program arrives
     Message(Message),
 }
@@ -292,20 +2164,46 @@ pub enum Event {
 /// This iterator returns a [Result] with either the next [Event] or an [Error]. Errors are not
 /// fatal and the user and handle (or not handle) as they see fit.
 pub struct MsgIterator {
-    addr: SocketAddr,
-    stream: Option<TcpStream>,
+    addr: Addr,
+    stream: Option<Stream>,
     buffer: ByteBuffer,
+    // The hello is read while connecting but returned by the call after `Connected`
+    hello: Option<Hello>,
+    callsites: Callsites,
 }
 
 impl MsgIterator {
     /// Create a new message iterator to a custom destination IP and port
     #[inline]
     pub fn new(ip: &str, port: u16) -> Result<Self, AddrParseError> {
-        Ok(Self {
-            addr: SocketAddr::new(IpAddr::from_str(ip)?, port),
+        Ok(Self::from_addr(Addr::Tcp(SocketAddr::new(
+            IpAddr::from_str(ip)?,
+            port,
+        ))))
+    }
+
+    /// Create a new message iterator to a Unix domain socket path
+    #[cfg(unix)]
+    #[inline]
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self::from_addr(Addr::Unix(path.into()))
+    }
+
+    #[inline]
+    fn from_addr(addr: Addr) -> Self {
+        Self {
+            addr,
             stream: None,
             buffer: ByteBuffer::new(BUFFER_SIZE),
-        })
+            hello: None,
+            callsites: Callsites::new(),
+        }
+    }
+
+    /// The address of the debugged program this iterator connects to
+    #[inline]
+    pub fn addr(&self) -> &Addr {
+        &self.addr
     }
 }
 
@@ -320,43 +2218,39 @@ impl Iterator for MsgIterator {
     type Item = Result<Event, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(hello) = self.hello.take() {
+            return Some(Ok(Event::Hello(hello)));
+        }
+
         match &mut self.stream {
-            Some(stream) => match self.buffer.read_from_stream(stream, LEN_FIELD_SIZE) {
-                Ok(_) => {
-                    // We know this is long enough - guaranteed by read above
-                    let len = self.buffer.read_u32().unwrap();
-
-                    match self
-                        .buffer
-                        .read_from_stream(stream, len as usize - LEN_FIELD_SIZE)
-                    {
-                        Ok(_) => match Message::from_buffer(&mut self.buffer) {
-                            Ok(msg) => Some(Ok(Event::Message(msg))),
-                            Err(err) => {
-                                self.stream = None;
-                                Some(Err(err))
-                            }
-                        },
-                        Err(_) => {
-                            self.stream = None;
-                            Some(Ok(Event::Disconnected(self.addr)))
-                        }
-                    }
+            Some(stream) => match read_message(stream, &mut self.buffer, &mut self.callsites) {
+                Ok(Ok(msg)) => Some(Ok(Event::Message(msg))),
+                Ok(Err(err)) => {
+                    self.stream = None;
+                    Some(Err(err))
                 }
                 Err(_) => {
                     self.stream = None;
-                    Some(Ok(Event::Disconnected(self.addr)))
+                    Some(Ok(Event::Disconnected(self.addr.clone())))
                 }
             },
             None => loop {
-                if let Ok(mut stream) = TcpStream::connect(self.addr) {
-                    match self.buffer.read_from_stream(&mut stream, size_of::<u8>()) {
-                        // We know this is long enough - guaranteed by read above
-                        Ok(_) if self.buffer.read_u8().unwrap() == WIRE_PROTOCOL_VERSION => {
-                            self.stream = Some(stream);
-                            return Some(Ok(Event::Connected(self.addr)));
-                        }
-                        Ok(_) => return Some(Err(Error::BadVersion)),
+                if let Ok(mut stream) = Stream::connect(&self.addr) {
+                    match read_version(&mut stream, &mut self.buffer) {
+                        Ok(true) => match read_hello(&mut stream, &mut self.buffer) {
+                            Ok(Ok(hello)) => {
+                                self.stream = Some(stream);
+                                self.hello = Some(hello);
+                                // Callsite IDs are only valid for the connection they were sent on
+                                self.callsites.clear();
+                                return Some(Ok(Event::Connected(self.addr.clone())));
+                            }
+                            Ok(Err(err)) => return Some(Err(err)),
+                            Err(_) => {
+                                // No op
+                            }
+                        },
+                        Ok(false) => return Some(Err(Error::BadVersion)),
                         Err(_) => {
                             // No op
                         }
@@ -368,3 +2262,142 @@ impl Iterator for MsgIterator {
         }
     }
 }
+
+// *** Source ***
+
+/// Identifies which debugged program an [Event] returned by [MsgListener] came from
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Source {
+    /// A unique ID for this connection (assigned in connection order starting at 1)
+    pub id: u64,
+    /// The address of the debugged program
+    pub addr: Addr,
+}
+
+// *** MsgListener ***
+
+/// An iterator that listens for debugged programs connecting to it (via `rdbg::connect`) and
+/// returns [Event]s from all of them, each tagged with its [Source]. This is the counterpart
+/// to [MsgIterator] for when the debugged program does the connecting.
+///
+/// This iterator never completes (so [Option] is never `None`). It accepts any number of
+/// programs, each of which will return its own [Event::Connected] and [Event::Disconnected].
+///
+/// Like [MsgIterator], errors are not fatal, however, a program that returns an error is
+/// disconnected.
+pub struct MsgListener {
+    addr: Addr,
+    receiver: Receiver<(Source, Result<Event, Error>)>,
+}
+
+impl MsgListener {
+    /// Create a new message listener on a custom IP and port
+    pub fn bind(ip: &str, port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((ip, port))?;
+        let addr = Addr::Tcp(listener.local_addr()?);
+        Ok(Self::spawn(Listener::Tcp(listener), addr))
+    }
+
+    /// Create a new message listener on a Unix domain socket path. Any existing socket file at
+    /// this path is replaced.
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        remove_stale_socket(&path)?;
+        let listener = UnixListener::bind(&path)?;
+        Ok(Self::spawn(
+            Listener::Unix(listener, path.clone()),
+            Addr::Unix(path),
+        ))
+    }
+
+    fn spawn(listener: Listener, addr: Addr) -> Self {
+        let (sender, receiver) = channel();
+        thread::spawn(move || accept_programs(&listener, &sender));
+        Self { addr, receiver }
+    }
+
+    /// The address this listener is listening on
+    #[inline]
+    pub fn addr(&self) -> &Addr {
+        &self.addr
+    }
+}
+
+impl Iterator for MsgListener {
+    type Item = (Source, Result<Event, Error>);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        // The accepting thread never exits, so this should never fail
+        self.receiver.recv().ok()
+    }
+}
+
+fn accept_programs(listener: &Listener, events: &Sender<(Source, Result<Event, Error>)>) {
+    let mut next_id = 1;
+
+    loop {
+        if let Ok((stream, addr)) = listener.accept() {
+            let source = Source { id: next_id, addr };
+            next_id += 1;
+
+            let events = events.clone();
+            thread::spawn(move || read_program(stream, &source, &events));
+        }
+    }
+}
+
+fn read_program(
+    mut stream: Stream,
+    source: &Source,
+    events: &Sender<(Source, Result<Event, Error>)>,
+) {
+    let mut buffer = ByteBuffer::new(BUFFER_SIZE);
+    let mut callsites = Callsites::new();
+    // If the listener has been dropped there is nobody left to read our events
+    let send = |result| events.send((source.clone(), result)).is_ok();
+
+    match read_version(&mut stream, &mut buffer) {
+        Ok(true) => {}
+        Ok(false) => {
+            send(Err(Error::BadVersion));
+            return;
+        }
+        Err(_) => return,
+    }
+
+    match read_hello(&mut stream, &mut buffer) {
+        Ok(Ok(hello)) => {
+            if !send(Ok(Event::Connected(source.addr.clone()))) || !send(Ok(Event::Hello(hello))) {
+                return;
+            }
+        }
+        Ok(Err(err)) => {
+            send(Err(err));
+            return;
+        }
+        Err(_) => return,
+    }
+
+    loop {
+        match read_message(&mut stream, &mut buffer, &mut callsites) {
+            Ok(Ok(msg)) => {
+                if !send(Ok(Event::Message(msg))) {
+                    return;
+                }
+            }
+            Ok(Err(err)) => {
+                // We can't trust anything else on this connection after a bad message
+                if send(Err(err)) {
+                    send(Ok(Event::Disconnected(source.addr.clone())));
+                }
+                return;
+            }
+            Err(_) => {
+                send(Ok(Event::Disconnected(source.addr.clone())));
+                return;
+            }
+        }
+    }
+}
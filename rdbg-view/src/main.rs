@@ -1,71 +1,268 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
 use clap::Parser;
-use rdbg_client::{Error, Event, Message, MsgIterator, MsgPayload, DEFAULT_ADDR, DEFAULT_PORT};
+use rdbg_client::{
+    Error, Event, Frame, Level, Message, MsgIterator, MsgListener, MsgPayload, Source,
+    DEFAULT_ADDR, DEFAULT_PORT,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Remote hostname of debugged program
+    /// Remote hostname of debugged program (or local address to listen on with --listen)
     #[arg(default_value_t = DEFAULT_ADDR.to_string())]
     hostname: String,
 
-    /// Remote port on debugged program
+    /// Remote port on debugged program (or local port to listen on with --listen)
     #[arg(short, long, default_value_t = DEFAULT_PORT)]
     port: u16,
 
+    /// Unix domain socket path of debugged program (instead of hostname/port)
+    #[arg(short, long, conflicts_with_all = ["hostname", "port"])]
+    unix: Option<PathBuf>,
+
+    /// Listen for debugged programs connecting to us (via `rdbg::connect`)
+    #[arg(short, long, default_value_t = false)]
+    listen: bool,
+
     /// Use debug formatting for messages (:#? formatting style)
     #[arg(short, long, default_value_t = false)]
     debug_fmt: bool,
+
+    /// Only show messages at this level or more severe (error, warn, info, debug, trace)
+    #[arg(short = 'L', long, default_value = "trace", value_parser = parse_level)]
+    level: Level,
+
+    /// Only show messages with this tag (can be given more than once)
+    #[arg(short, long)]
+    tag: Vec<String>,
+
+    /// Only show messages from this module or its submodules (ex: my_crate::db, can be given more
+    /// than once)
+    #[arg(short, long)]
+    module: Vec<String>,
+
+    /// Show every backtrace frame instead of folding frames outside the debugged program
+    #[arg(short, long, default_value_t = false)]
+    backtrace_full: bool,
+
+    /// Truncate table cells longer than this many characters
+    #[arg(short = 'w', long, default_value_t = 32)]
+    column_width: usize,
+
+    /// Don't colour the level of each message (the default when output isn't a terminal)
+    #[arg(long, default_value_t = false)]
+    no_color: bool,
+}
+
+fn parse_level(s: &str) -> Result<Level, String> {
+    s.parse().map_err(|_| format!("unknown level: {s}"))
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-    eprintln!(
-        "*** Trying to connect to {}:{}... ***",
-        &args.hostname, args.port
-    );
+    let mut args = Args::parse();
+    args.no_color |= !std::io::stdout().is_terminal();
 
-    let iterator = MsgIterator::new(&args.hostname, args.port)?;
+    if args.listen {
+        let listener = match &args.unix {
+            Some(path) => unix_listener(path)?,
+            None => MsgListener::bind(&args.hostname, args.port)?,
+        };
+        eprintln!("*** Listening on {}... ***", listener.addr());
 
-    for event in iterator {
-        match event {
-            Ok(event) => match event {
-                Event::Connected(addr) => {
-                    eprintln!("*** Connected to {addr} ***");
-                }
-                Event::Disconnected(addr) => {
-                    eprintln!("*** Disconnected from {addr} ***");
-                }
-                Event::Message(msg) => {
+        let mut state = State::default();
+        for (source, event) in listener {
+            // Errors only affect the program that sent them, so just keep going
+            process_event(&args, Some(&source), event, &mut state);
+        }
+    } else {
+        let iterator = match &args.unix {
+            Some(path) => unix_iterator(path)?,
+            None => MsgIterator::new(&args.hostname, args.port)?,
+        };
+        eprintln!("*** Trying to connect to {}... ***", iterator.addr());
+
+        let mut state = State::default();
+        for event in iterator {
+            if !process_event(&args, None, event, &mut state) {
+                break;
+            }
+        }
+    }
+
+    eprintln!("*** Exiting... ***");
+    Ok(())
+}
+
+// Returns false if no further events should be processed
+fn process_event(
+    args: &Args,
+    source: Option<&Source>,
+    event: Result<Event, Error>,
+    state: &mut State,
+) -> bool {
+    // When listening, we prefix everything with the ID of the program it came from
+    let prefix = match source {
+        Some(source) => format!("#{} ", source.id),
+        None => String::new(),
+    };
+    let source = source.map(|source| source.id);
+
+    match event {
+        Ok(event) => match event {
+            Event::Connected(addr) => {
+                eprintln!("*** {prefix}Connected to {addr} ***");
+            }
+            Event::Hello(hello) => {
+                // A session we have seen before means the same run of the program reconnected
+                let run = if !state.sessions.insert(hello.session_id) {
+                    "reconnected"
+                } else if state.sessions.len() > 1 && source.is_none() {
+                    "restarted"
+                } else {
+                    "started"
+                };
+                let exe = Path::new(&hello.exe)
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default();
+
+                eprintln!(
+                    "*** {prefix}{exe} {run} (PID {}, args {:?}, {} {}, T:{}, session {}) ***",
+                    hello.pid,
+                    hello.args,
+                    hello.crate_name,
+                    hello.crate_version,
+                    hello.start_time_ns / 1_000_000,
+                    hello.session_uuid()
+                );
+            }
+            Event::Disconnected(addr) => {
+                eprintln!("*** {prefix}Disconnected from {addr} ***");
+                state.spans.remove_source(source);
+                state.timings.remove_source(source);
+                state.counters.remove_source(source);
+            }
+            Event::Message(msg) => {
+                // State is tracked even if messages aren't shown so span paths and stats stay
+                // complete
+                state.spans.add(source, &msg.payload);
+                state.timings.add(source, &msg.payload);
+                state.counters.add(source, &msg.payload);
+
+                if show_message(args, &msg) {
                     if args.debug_fmt {
-                        println!("{msg:#?}");
+                        println!("{prefix}{msg:#?}");
                     } else {
-                        print_message(&msg);
+                        print_message(&prefix, &msg, args, state, source);
                     }
                 }
-            },
-            Err(err) => match err {
-                Error::BadVersion => {
-                    eprintln!("*** Bad version (we only understand wire protocol 1) ***");
-                    break;
-                }
-                Error::BadUtf8(err) => {
-                    eprintln!("*** Bad UTF8 found in string ({err}) ***");
-                }
-                Error::CorruptMsg => {
-                    eprintln!("*** Corrupt message received ***");
-                }
-            },
-        }
+
+                state.spans.remove(source, &msg.payload);
+            }
+        },
+        Err(err) => match &err {
+            Error::BadVersion => {
+                eprintln!("*** {prefix}Bad version ({err}) ***");
+                return false;
+            }
+            Error::BadUtf8(err) => {
+                eprintln!("*** {prefix}Bad UTF8 found in string ({err}) ***");
+            }
+            Error::CorruptMsg => {
+                eprintln!("*** {prefix}Corrupt message received ***");
+            }
+        },
     }
 
-    eprintln!("*** Exiting... ***");
-    Ok(())
+    true
+}
+
+#[cfg(unix)]
+fn unix_iterator(path: &PathBuf) -> Result<MsgIterator, Box<dyn std::error::Error>> {
+    Ok(MsgIterator::unix(path))
+}
+
+#[cfg(not(unix))]
+fn unix_iterator(_path: &PathBuf) -> Result<MsgIterator, Box<dyn std::error::Error>> {
+    Err("Unix domain sockets are not supported on this platform".into())
+}
+
+#[cfg(unix)]
+fn unix_listener(path: &PathBuf) -> Result<MsgListener, Box<dyn std::error::Error>> {
+    Ok(MsgListener::bind_unix(path)?)
+}
+
+#[cfg(not(unix))]
+fn unix_listener(_path: &PathBuf) -> Result<MsgListener, Box<dyn std::error::Error>> {
+    Err("Unix domain sockets are not supported on this platform".into())
 }
 
-fn print_message(msg: &Message) {
+fn show_message(args: &Args, msg: &Message) -> bool {
+    let in_modules = |path: &str| args.module.iter().any(|module| in_module(path, module));
+
+    msg.level <= args.level
+        && (args.tag.is_empty() || matches!(&msg.tag, Some(tag) if args.tag.contains(tag)))
+        && (args.module.is_empty() || msg.module_path.as_deref().is_some_and(in_modules))
+}
+
+// Whether `path` is `module` or one of its submodules
+fn in_module(path: &str, module: &str) -> bool {
+    match path.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+fn level_color(level: Level) -> &'static str {
+    // ANSI SGR foreground colours
+    match level {
+        Level::Error => "31",
+        Level::Warn => "33",
+        Level::Info => "32",
+        Level::Debug => "34",
+        Level::Trace => "90",
+    }
+}
+
+fn print_fields(fields: &[(String, impl Display)]) {
+    for (key, value) in fields {
+        print!(" |{key}->{value}|")
+    }
+    println!();
+}
+
+fn print_message(prefix: &str, msg: &Message, args: &Args, state: &State, source: Option<u64>) {
+    let span_path = |id| state.spans.path(source, id);
+
+    let level = if !args.no_color {
+        format!("\x1b[{}m{:<5}\x1b[0m", level_color(msg.level), msg.level)
+    } else {
+        format!("{:<5}", msg.level)
+    };
+
+    let tag = match &msg.tag {
+        Some(tag) => format!("[{tag}] "),
+        None => String::new(),
+    };
+
+    let thread = match &msg.thread_name {
+        Some(name) => format!("{name}({})", msg.thread_id),
+        None => msg.thread_id.clone(),
+    };
+
+    let location = match msg.column {
+        Some(column) => format!("{}:{}:{column}", msg.filename, msg.line),
+        None => format!("{}:{}", msg.filename, msg.line),
+    };
+
     print!(
-        "T:{} THR:{} {}:{}",
-        msg.time, msg.thread_id, msg.filename, msg.line
+        "{prefix}T:{} THR:{thread} {level} {tag}{location}",
+        msg.time_ns / 1_000_000
     );
 
     match &msg.payload {
@@ -77,11 +274,348 @@ fn print_message(msg: &Message) {
 
             println!(" {}", msg);
         }
-        MsgPayload::Values(values) => {
-            for (key, value) in values {
-                print!(" |{key}->{value}|")
+        MsgPayload::Values(values) => print_fields(values),
+        MsgPayload::Dropped(count) => {
+            println!(" *** {count} message(s) dropped (queue full) ***");
+        }
+        MsgPayload::HistoryEnd(count) => {
+            println!(" *** End of history ({count} message(s) replayed) - live from here ***");
+        }
+        MsgPayload::SpanNew { id, fields, .. } => {
+            print!(" span {}", span_path(*id));
+            print_fields(fields);
+        }
+        MsgPayload::SpanEnter(id) => println!(" -> {}", span_path(*id)),
+        MsgPayload::SpanExit(id) => println!(" <- {}", span_path(*id)),
+        MsgPayload::SpanClose(id) => println!(" span {} closed", span_path(*id)),
+        MsgPayload::SpanRecord { id, fields } => {
+            print!(" span {}", span_path(*id));
+            print_fields(fields);
+        }
+        MsgPayload::Event { span, fields } => {
+            if let Some(span) = span {
+                print!(" [{}]", span_path(*span));
+            }
+
+            // The formatted message reads better on its own than as a field
+            let (message, fields): (Vec<_>, Vec<_>) = fields
+                .iter()
+                .cloned()
+                .partition(|(key, _)| key == "message");
+            if let Some((_, message)) = message.first() {
+                print!(" {message}");
+            }
+            print_fields(&fields);
+        }
+        MsgPayload::Panic {
+            message,
+            thread,
+            backtrace,
+        } => {
+            println!(" *** Thread '{thread}' panicked: {message} ***");
+            print_frames(backtrace, args);
+        }
+        MsgPayload::Backtrace(frames) => {
+            println!(" backtrace:");
+            print_frames(frames, args);
+        }
+        MsgPayload::Timing {
+            label, duration, ..
+        } => {
+            print!(" {label} took {duration:?}");
+            match state.timings.get(source, label) {
+                Some(stats) if stats.count > 1 => println!(
+                    " (min {:?} / mean {:?} / max {:?} over {})",
+                    stats.min,
+                    stats.total / stats.count,
+                    stats.max,
+                    stats.count
+                ),
+                _ => println!(),
+            }
+        }
+        MsgPayload::Counter { name, delta } => {
+            let total = state.counters.get(source, name);
+            println!(" {name} {delta:+} (total {total})");
+        }
+        MsgPayload::Gauge { name, value } => println!(" {name} = {value}"),
+        MsgPayload::StructuredValues(values) => print_fields(values),
+        MsgPayload::Bytes { label, data } => {
+            println!(" {label} ({} byte(s))", data.len());
+            print_hexdump(data);
+        }
+        MsgPayload::Table { headers, rows } => {
+            println!(" table ({} row(s))", rows.len());
+            print_table(headers, rows, args.column_width);
+        }
+    }
+}
+
+fn print_table(headers: &[String], rows: &[Vec<String>], column_width: usize) {
+    // Rows can be ragged, so the widest of them decides the number of columns
+    let columns = rows.iter().map(Vec::len).fold(headers.len(), usize::max);
+
+    let truncate = |cell: &str| -> String {
+        if cell.chars().count() > column_width {
+            let mut cell: String = cell.chars().take(column_width.saturating_sub(1)).collect();
+            cell.push('…');
+            cell
+        } else {
+            cell.to_string()
+        }
+    };
+    let headers: Vec<_> = headers.iter().map(|cell| truncate(cell)).collect();
+    let rows: Vec<Vec<_>> = rows
+        .iter()
+        .map(|row| row.iter().map(|cell| truncate(cell)).collect())
+        .collect();
+
+    let mut widths = vec![0; columns];
+    for row in rows.iter().chain([&headers]) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let print_row = |row: &[String]| {
+        let line: Vec<_> = widths
+            .iter()
+            .enumerate()
+            .map(|(idx, width)| {
+                let cell = row.get(idx).map(String::as_str).unwrap_or_default();
+                format!("{cell:<width$}")
+            })
+            .collect();
+        println!("  {}", line.join("  ").trim_end());
+    };
+
+    print_row(&headers);
+    let separator: Vec<_> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    print_row(&separator);
+    for row in &rows {
+        print_row(row);
+    }
+}
+
+// Classic offset/hex/ASCII layout (like `hexdump -C`)
+fn print_hexdump(data: &[u8]) {
+    const BYTES_PER_LINE: usize = 16;
+
+    for (line, chunk) in data.chunks(BYTES_PER_LINE).enumerate() {
+        let mut hex = String::with_capacity(BYTES_PER_LINE * 3 + 1);
+        for (idx, byte) in chunk.iter().enumerate() {
+            // Extra space between the two halves of the line
+            if idx == BYTES_PER_LINE / 2 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{byte:02x} "));
+        }
+
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+
+        println!(
+            "  {:08x}  {hex:<width$} |{ascii}|",
+            line * BYTES_PER_LINE,
+            width = BYTES_PER_LINE * 3 + 1
+        );
+    }
+}
+
+// Frames of std and dependencies are rarely interesting, so anything else is assumed to be from
+// the debugged program itself
+fn is_program_frame(frame: &Frame) -> bool {
+    const LIBRARY_PATHS: [&str; 4] = ["/rustc/", "/.cargo/registry/", "/.cargo/git/", "/.rustup/"];
+
+    match &frame.file {
+        Some(file) => !LIBRARY_PATHS
+            .iter()
+            .any(|path| file.replace('\\', "/").contains(path)),
+        None => false,
+    }
+}
+
+fn print_frames(frames: &[Frame], args: &Args) {
+    let mut folded = 0;
+
+    for (idx, frame) in frames.iter().enumerate() {
+        let program_frame = is_program_frame(frame);
+
+        if !program_frame && !args.backtrace_full {
+            folded += 1;
+            continue;
+        }
+        if folded > 0 {
+            println!("      ... {folded} frame(s) folded ...");
+            folded = 0;
+        }
+
+        let location = match (&frame.file, frame.line) {
+            (Some(file), Some(line)) => format!(" ({file}:{line})"),
+            _ => String::new(),
+        };
+        if program_frame && !args.no_color {
+            // Bold
+            println!("  {idx:>4}: \x1b[1m{}\x1b[0m{location}", frame.function);
+        } else {
+            println!("  {idx:>4}: {}{location}", frame.function);
+        }
+    }
+
+    if folded > 0 {
+        println!("      ... {folded} frame(s) folded ...");
+    }
+}
+
+// *** State ***
+
+#[derive(Default)]
+struct State {
+    spans: Spans,
+    timings: Timings,
+    counters: Counters,
+    // The session ID of every program run seen so far
+    sessions: HashSet<u128>,
+}
+
+// *** Counters ***
+
+// Keeps the running total of each counter of each program
+#[derive(Default)]
+struct Counters(HashMap<(Option<u64>, String), i64>);
+
+impl Counters {
+    fn add(&mut self, source: Option<u64>, payload: &MsgPayload) {
+        if let MsgPayload::Counter { name, delta } = payload {
+            let total = self.0.entry((source, name.clone())).or_default();
+            *total = total.wrapping_add(*delta);
+        }
+    }
+
+    #[inline]
+    fn get(&self, source: Option<u64>, name: &str) -> i64 {
+        self.0
+            .get(&(source, name.to_string()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn remove_source(&mut self, source: Option<u64>) {
+        self.0
+            .retain(|(counter_source, _), _| *counter_source != source);
+    }
+}
+
+// *** Timings ***
+
+struct TimingStats {
+    count: u32,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+// Aggregates the timings of each program by label
+#[derive(Default)]
+struct Timings(HashMap<(Option<u64>, String), TimingStats>);
+
+impl Timings {
+    fn add(&mut self, source: Option<u64>, payload: &MsgPayload) {
+        if let MsgPayload::Timing {
+            label, duration, ..
+        } = payload
+        {
+            let stats = self
+                .0
+                .entry((source, label.clone()))
+                .or_insert(TimingStats {
+                    count: 0,
+                    total: Duration::ZERO,
+                    min: Duration::MAX,
+                    max: Duration::ZERO,
+                });
+
+            stats.count += 1;
+            stats.total += *duration;
+            stats.min = stats.min.min(*duration);
+            stats.max = stats.max.max(*duration);
+        }
+    }
+
+    #[inline]
+    fn get(&self, source: Option<u64>, label: &str) -> Option<&TimingStats> {
+        self.0.get(&(source, label.to_string()))
+    }
+
+    fn remove_source(&mut self, source: Option<u64>) {
+        self.0
+            .retain(|(stats_source, _), _| *stats_source != source);
+    }
+}
+
+// *** Spans ***
+
+struct Span {
+    name: String,
+    parent: Option<u64>,
+}
+
+// Tracks the `tracing` spans of each program so they can be shown by their path in the call tree
+#[derive(Default)]
+struct Spans(HashMap<(Option<u64>, u64), Span>);
+
+impl Spans {
+    fn add(&mut self, source: Option<u64>, payload: &MsgPayload) {
+        if let MsgPayload::SpanNew {
+            id, parent, name, ..
+        } = payload
+        {
+            let span = Span {
+                name: name.clone(),
+                parent: *parent,
+            };
+            self.0.insert((source, *id), span);
+        }
+    }
+
+    fn remove(&mut self, source: Option<u64>, payload: &MsgPayload) {
+        if let MsgPayload::SpanClose(id) = payload {
+            self.0.remove(&(source, *id));
+        }
+    }
+
+    fn remove_source(&mut self, source: Option<u64>) {
+        self.0.retain(|(span_source, _), _| *span_source != source);
+    }
+
+    // Returns the names of the span and its ancestors (ex: `request>db>query`)
+    fn path(&self, source: Option<u64>, id: u64) -> String {
+        let mut names = Vec::new();
+        let mut next = Some(id);
+
+        while let Some(id) = next {
+            match self.0.get(&(source, id)) {
+                Some(span) => {
+                    names.push(span.name.as_str());
+                    next = span.parent;
+                }
+                // We may have connected after the span was created
+                None => {
+                    names.push("?");
+                    break;
+                }
             }
-            println!();
         }
+
+        names.reverse();
+        names.join(">")
     }
 }
@@ -0,0 +1,115 @@
+// Measures how many messages per second can be sent as the number of sending threads grows. A
+// viewer that decodes and counts everything it reads is attached, and each run checks that every
+// message sent was received and none were dropped.
+//
+// cargo bench -p rdbg --bench throughput
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rdbg_client::{Event, MsgIterator, MsgPayload};
+
+const PORT: u16 = 13650;
+const THREAD_COUNTS: [usize; 5] = [1, 2, 4, 8, 16];
+const MSGS_PER_THREAD: usize = 100_000;
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct Received {
+    msgs: AtomicU64,
+    dropped: AtomicU64,
+}
+
+fn attach_viewer() -> Arc<Received> {
+    let received = Arc::new(Received::default());
+    let counts = received.clone();
+    let mut viewer = MsgIterator::new("127.0.0.1", PORT).unwrap();
+
+    thread::spawn(move || {
+        for event in &mut viewer {
+            match event {
+                Ok(Event::Message(msg)) => match msg.payload {
+                    MsgPayload::Dropped(count) => {
+                        counts.dropped.fetch_add(count, Ordering::Relaxed);
+                    }
+                    _ => {
+                        counts.msgs.fetch_add(1, Ordering::Relaxed);
+                    }
+                },
+                Ok(Event::Disconnected(_)) | Err(_) => panic!("Viewer lost its connection"),
+                Ok(_) => {}
+            }
+        }
+    });
+    received
+}
+
+fn run(debug: &rdbg::RemoteDebug, received: &Received, threads: usize) -> Duration {
+    let before = received.msgs.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for i in 0..MSGS_PER_THREAD {
+                    rdbg::msg!(debug, ["message {i}"]);
+                }
+            });
+        }
+    });
+    debug.flush();
+    let elapsed = start.elapsed();
+
+    // Make sure the messages were actually sent rather than dropped somewhere along the way
+    let expected = before + (threads * MSGS_PER_THREAD) as u64;
+    let waiting = Instant::now();
+    loop {
+        let dropped = received.dropped.load(Ordering::Relaxed);
+        assert_eq!(
+            0, dropped,
+            "Viewer was told {dropped} messages were dropped"
+        );
+
+        let msgs = received.msgs.load(Ordering::Relaxed);
+        assert!(
+            msgs <= expected,
+            "Viewer received {} messages but only {} were sent",
+            msgs - before,
+            expected - before
+        );
+        if msgs == expected {
+            break;
+        }
+
+        assert!(
+            waiting.elapsed() < RECEIVE_TIMEOUT,
+            "Viewer only received {} of {} messages",
+            msgs - before,
+            expected - before
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    elapsed
+}
+
+fn main() {
+    let debug = rdbg::port(PORT);
+    let received = attach_viewer();
+
+    // Warm everything up
+    run(&debug, &received, 1);
+
+    println!("{:>8} {:>14} {:>10}", "threads", "msgs/sec", "scaling");
+    let mut base = None;
+
+    for threads in THREAD_COUNTS {
+        let elapsed = run(&debug, &received, threads);
+        let rate = (threads * MSGS_PER_THREAD) as f64 / elapsed.as_secs_f64();
+        let base = *base.get_or_insert(rate);
+
+        println!("{threads:>8} {rate:>14.0} {:>9.2}x", rate / base);
+    }
+}
@@ -1,11 +1,50 @@
+use std::backtrace::Backtrace;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Arguments, Display, Formatter};
+#[cfg(unix)]
+use std::fs::{self, DirBuilder, Permissions};
+use std::hash::{BuildHasher, Hasher};
 use std::io::Write;
-use std::mem::size_of;
-use std::net::{TcpListener, TcpStream};
-use std::process::exit;
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use std::sync::{Arc, Condvar, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{io, thread};
+use std::iter;
+use std::marker::PhantomData;
+use std::mem::{self, size_of};
+#[cfg(feature = "enabled")]
+use std::net::IpAddr;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic;
+// Renamed to `PanicHookInfo` in 1.81, but the old name keeps our MSRV down
+#[allow(deprecated)]
+use std::panic::PanicInfo;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::process::{self, exit};
+use std::str::FromStr;
+use std::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, RwLock};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, io};
+
+#[cfg(feature = "log")]
+pub mod log;
+mod ring;
+#[cfg(feature = "tracing")]
+pub mod tracing;
+#[cfg(feature = "serde")]
+mod value;
+
+#[cfg(feature = "serde")]
+pub use value::encode_value;
+
+use crate::ring::Ring;
 
 #[cfg(feature = "insecure-remote")]
 const BIND_ADDR: &str = "0.0.0.0";
@@ -13,16 +52,94 @@ const BIND_ADDR: &str = "0.0.0.0";
 const BIND_ADDR: &str = "127.0.0.1";
 const DEFAULT_PORT: u16 = 13579;
 
-const CHAN_MAX_MESSAGES: usize = 32;
+const DISABLE_VAR: &str = "RDBG_DISABLE";
+const ADDR_VAR: &str = "RDBG_ADDR";
+const PORT_VAR: &str = "RDBG_PORT";
+#[cfg(unix)]
+const UNIX_VAR: &str = "RDBG_UNIX";
+const CONNECT_VAR: &str = "RDBG_CONNECT";
+const POLICY_VAR: &str = "RDBG_POLICY";
+const HISTORY_VAR: &str = "RDBG_HISTORY";
+
+const CONNECT_WAIT_TIME: u64 = 250; // Milliseconds
+const PANIC_WAIT_TIME: u64 = 1000; // Milliseconds
+const QUEUE_MAX_MESSAGES: usize = 32;
+const CLIENT_MAX_MESSAGES: usize = 1024;
+const CLIENT_WAIT_TIME: u64 = 250; // Milliseconds
+const BATCH_MAX_BYTES: usize = 64 * 1024;
+const BATCH_MAX_LATENCY: u64 = 1; // Milliseconds
 const LEN_FIELD_SIZE: usize = size_of::<u32>();
-const WIRE_PROTOCOL_VERSION: u8 = 1;
+const WIRE_PROTOCOL_VERSION: u8 = 5;
 const THREAD_ID_PREFIX: &str = "ThreadId";
+const CRATE_NAME: &str = env!("CARGO_PKG_NAME");
+const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[cfg(unix)]
+const SOCKET_PERMISSIONS: u32 = 0o600;
+#[cfg(unix)]
+const PRIVATE_DIR_PERMISSIONS: u32 = 0o700;
 
-static REMOTE_DEBUG: Mutex<Option<RemoteDebug>> = Mutex::new(None);
+static REMOTE_DEBUG: Mutex<Registry> = Mutex::new(Registry::new());
+
+thread_local! {
+    // The tag used by messages on this thread that weren't sent with one explicitly
+    static SCOPED_TAG: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
 
 // *** msg / vals macros ***
 
-/// Send a debug message to the remote viewer
+// Each use gets its own static, so after the first message sent from it, finding its ID is a
+// single atomic load. Any names given are those of the values sent from it (see `vals!`).
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __callsite {
+    ($($name:expr),* $(,)?) => {{
+        static CALLSITE: $crate::Callsite = $crate::Callsite::new(
+            file!(),
+            line!(),
+            column!(),
+            module_path!(),
+            &[$($name),*],
+        );
+        &CALLSITE
+    }};
+}
+
+#[doc(hidden)]
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! __msg {
+    ($level:expr, $port:expr, [ $($arg:tt)* ]) => {
+        if $crate::level_enabled($level) {
+            $port.send_message(
+                $crate::__callsite!(),
+                $level,
+                $crate::MsgPayload::message(format_args!($($arg)*)),
+            );
+        }
+    };
+}
+
+#[doc(hidden)]
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! __vals {
+    ($level:expr, $port:expr, [ $($value:expr),+ ]) => {
+        if $crate::level_enabled($level) {
+            $port.send_message(
+                $crate::__callsite!($(stringify!($value)),+),
+                $level,
+                $crate::MsgPayload::Values(vec![$(
+                    match $value {
+                        val => format!("{:#?}", &val),
+                    }
+                ),+]),
+            );
+        }
+    };
+}
+
+/// Send a debug message to the remote viewer (at [Level::Info])
 ///
 /// ```dontrun
 /// // Default port
@@ -39,13 +156,11 @@ static REMOTE_DEBUG: Mutex<Option<RemoteDebug>> = Mutex::new(None);
 #[macro_export]
 macro_rules! msg {
     ($port:expr, [ $($arg:tt)* ]) => {
-        $port.send_message(file!(), line!(), $crate::MsgPayload::Message(
-            std::fmt::format(format_args!($($arg)*))
-        ))
+        $crate::__msg!($crate::Level::Info, $port, [ $($arg)* ])
     };
 
     ($($arg:tt)*) => {
-        $crate::msg!($crate::RemoteDebug::default(), [ $($arg)* ])
+        $crate::msg!($crate::RemoteDebug::default_ref(), [ $($arg)* ])
     };
 }
 
@@ -56,7 +171,7 @@ macro_rules! msg {
     ($($arg:tt)*) => {};
 }
 
-/// Send a debug message to the remote viewer (with flush)
+/// Send a debug message to the remote viewer (at [Level::Info], with flush)
 ///
 /// ```dontrun
 /// // Default port
@@ -73,14 +188,12 @@ macro_rules! msg {
 #[macro_export]
 macro_rules! msgf {
     ($port:expr, [ $($arg:tt)* ]) => {{
-        $port.send_message(file!(), line!(), $crate::MsgPayload::Message(
-            std::fmt::format(format_args!($($arg)*))
-        ));
+        $crate::__msg!($crate::Level::Info, $port, [ $($arg)* ]);
         $port.flush();
     }};
 
     ($($arg:tt)*) => {
-        $crate::msgf!($crate::RemoteDebug::default(), [ $($arg)* ])
+        $crate::msgf!($crate::RemoteDebug::default_ref(), [ $($arg)* ])
     };
 }
 
@@ -91,7 +204,7 @@ macro_rules! msgf {
     ($($arg:tt)*) => {};
 }
 
-/// Send debug expression name/value pairs to the remote viewer
+/// Send debug expression name/value pairs to the remote viewer (at [Level::Debug])
 ///
 /// ```dontrun
 /// // Default port
@@ -108,17 +221,11 @@ macro_rules! msgf {
 #[macro_export]
 macro_rules! vals {
     ($port:expr, [ $($value:expr),+ $(,)? ]) => {
-        $port.send_message(file!(), line!(), $crate::MsgPayload::Values(vec![$((
-            match $value {
-                val => {
-                    (stringify!($value), format!("{:#?}", &val))
-                }
-            }
-        )),+]))
+        $crate::__vals!($crate::Level::Debug, $port, [ $($value),+ ])
     };
 
     ($($value:expr),+ $(,)?) => {
-        $crate::vals!($crate::RemoteDebug::default(), [ $($value),+ ])
+        $crate::vals!($crate::RemoteDebug::default_ref(), [ $($value),+ ])
     };
 }
 
@@ -129,7 +236,7 @@ macro_rules! vals {
     ($($value:expr),+ $(,)?) => {};
 }
 
-/// Send debug expression name/value pairs to the remote viewer (with flush)
+/// Send debug expression name/value pairs to the remote viewer (at [Level::Debug], with flush)
 ///
 /// ```dontrun
 /// // Default port
@@ -146,18 +253,12 @@ macro_rules! vals {
 #[macro_export]
 macro_rules! valsf {
     ($port:expr, [ $($value:expr),+ $(,)? ]) => {{
-        $port.send_message(file!(), line!(), $crate::MsgPayload::Values(vec![$((
-            match $value {
-                val => {
-                    (stringify!($value), format!("{:#?}", &val))
-                }
-            }
-        )),+]));
+        $crate::__vals!($crate::Level::Debug, $port, [ $($value),+ ]);
         $port.flush();
     }};
 
     ($($value:expr),+ $(,)?) => {
-        $crate::valsf!($crate::RemoteDebug::default(), [ $($value),+ ])
+        $crate::valsf!($crate::RemoteDebug::default_ref(), [ $($value),+ ])
     };
 }
 
@@ -168,403 +269,3778 @@ macro_rules! valsf {
     ($($value:expr),+ $(,)?) => {};
 }
 
-// *** Message related functions ***
+/// Send debug expression names and their values to the remote viewer (at [Level::Debug]). Unlike
+/// [vals], values are serialized with [serde](https://docs.rs/serde) so the viewer receives a
+/// structured value tree rather than text. Requires the `serde` feature.
+///
+/// ```dontrun
+/// // Default port
+/// let point = Point { x: 1, y: 2 };
+/// rdbg::svals!(point, vec![1, 2, 3]);
+/// flush();
+///
+/// // Custom port
+/// let debug = rdbg::port(5000);
+/// rdbg::svals!(&debug, [point, vec![1, 2, 3]]);
+/// debug.flush();
+/// ```
+#[cfg(all(feature = "enabled", feature = "serde"))]
+#[macro_export]
+macro_rules! svals {
+    ($port:expr, [ $($value:expr),+ $(,)? ]) => {
+        if $crate::level_enabled($crate::Level::Debug) {
+            $port.send_message(
+                $crate::__callsite!($(stringify!($value)),+),
+                $crate::Level::Debug,
+                $crate::MsgPayload::StructuredValues(vec![$($crate::encode_value(&$value)),+]),
+            );
+        }
+    };
 
-fn current_thread() -> String {
-    // This has to be made into a string as there doesn't seem to be a way to get any
-    // sort of integral version out of it (at least not in stable)
-    parse_thread_id(format!("{:?}", thread::current().id()))
+    ($($value:expr),+ $(,)?) => {
+        $crate::svals!($crate::RemoteDebug::default_ref(), [ $($value),+ ])
+    };
 }
 
-fn parse_thread_id(thread_id: String) -> String {
-    // We optimistically assume the current format, but if it isn't just return
-    // the initial string as-is
-    let mut split = thread_id.split(&['(', ')']);
-    if split.next() == Some(THREAD_ID_PREFIX) {
-        match split.next() {
-            Some(thread_id) if split.next() == Some("") => thread_id.to_string(),
-            _ => thread_id,
-        }
-    } else {
-        thread_id
-    }
+#[cfg(all(not(feature = "enabled"), feature = "serde"))]
+#[macro_export]
+macro_rules! svals {
+    ($port:expr, [ $($value:expr),+ $(,)? ]) => {};
+    ($($value:expr),+ $(,)?) => {};
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::parse_thread_id;
+// *** bytes macro ***
 
-    #[test]
-    fn parse_thread_current() {
-        assert_eq!(parse_thread_id("ThreadId(1)".to_string()), "1".to_string());
-    }
+/// Send a labeled binary buffer (anything that is `AsRef<[u8]>`) to the remote viewer (at
+/// [Level::Debug]). The bytes are sent as is, so the viewer can show them as a hex dump.
+///
+/// ```dontrun
+/// // Default port
+/// rdbg::bytes!("header", &buf[..16]);
+///
+/// // Custom port
+/// let debug = rdbg::port(5000);
+/// rdbg::bytes!(&debug, ["packet", &buf]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! bytes {
+    (@send $port:expr, $label:expr, $data:expr) => {
+        if $crate::level_enabled($crate::Level::Debug) {
+            $port.send_message(
+                $crate::__callsite!(),
+                $crate::Level::Debug,
+                $crate::MsgPayload::Bytes {
+                    label: $label,
+                    data: ::std::convert::AsRef::<[u8]>::as_ref(&$data).to_vec(),
+                },
+            );
+        }
+    };
 
-    #[test]
-    fn parse_thread_changed() {
-        assert_eq!(
-            parse_thread_id("Thread(1)".to_string()),
-            "Thread(1)".to_string()
-        );
-    }
-}
+    ($port:expr, [ $label:literal, $data:expr $(,)? ]) => {
+        $crate::bytes!(@send $port, ::std::borrow::Cow::Borrowed($label), $data)
+    };
 
-fn current_time() -> u64 {
-    // This can only really fail if time goes to before the epoch, which likely isn't possible
-    // on today's system clocks
-    // While this returns a u128, u64 ought to be large enough to hold all ms since the epoch
-    // for our lifetimes
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64
-}
+    ($port:expr, [ $label:expr, $data:expr $(,)? ]) => {
+        $crate::bytes!(@send $port, ::std::borrow::Cow::Owned(($label).into()), $data)
+    };
 
-#[inline]
-fn required_str_capacity(s: &str) -> usize {
-    s.as_bytes().len() + LEN_FIELD_SIZE
-}
+    ($label:literal, $data:expr $(,)?) => {
+        $crate::bytes!($crate::RemoteDebug::default_ref(), [$label, $data])
+    };
 
-// *** MsgPayloadVal ***
+    ($label:expr, $data:expr $(,)?) => {
+        $crate::bytes!($crate::RemoteDebug::default_ref(), [$label, $data])
+    };
+}
 
-#[repr(u8)]
-enum MsgPayloadVal {
-    Message = 1,
-    Values = 2,
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! bytes {
+    ($port:expr, [ $label:expr, $data:expr $(,)? ]) => {};
+    ($label:expr, $data:expr $(,)?) => {};
 }
 
-// *** MsgPayload ***
+// *** table macro ***
 
-#[doc(hidden)]
-#[derive(Clone, Debug)]
-pub enum MsgPayload {
-    // A formatted string
-    Message(String),
-    // A list of name/value pairs from expressions
-    Values(Vec<(&'static str, String)>),
+/// Send rows of data to the remote viewer as a table (at [Level::Debug]). The headers are any
+/// iterable of displayable values and the rows are any iterable of such iterables.
+///
+/// ```dontrun
+/// // Default port
+/// rdbg::table!(
+///     ["Name", "Age"],
+///     people.iter().map(|p| [p.name.to_string(), p.age.to_string()])
+/// );
+///
+/// // Custom port
+/// let debug = rdbg::port(5000);
+/// rdbg::table!(&debug, [["x", "x²"], (1..10).map(|x| [x, x * x])]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! table {
+    ($port:expr, [ $headers:expr, $rows:expr $(,)? ]) => {
+        if $crate::level_enabled($crate::Level::Debug) {
+            $port.send_message(
+                $crate::__callsite!(),
+                $crate::Level::Debug,
+                $crate::MsgPayload::Table {
+                    headers: $crate::table_row($headers),
+                    rows: ::std::iter::IntoIterator::into_iter($rows)
+                        .map($crate::table_row)
+                        .collect(),
+                },
+            );
+        }
+    };
+
+    ($headers:expr, $rows:expr $(,)?) => {
+        $crate::table!($crate::RemoteDebug::default_ref(), [$headers, $rows])
+    };
 }
 
-impl MsgPayload {
-    fn required_capacity(&self) -> usize {
-        (match self {
-            MsgPayload::Message(msg) => required_str_capacity(msg),
-            //  We start with 4 because we start by sending number of vec elements
-            MsgPayload::Values(values) => {
-                values.iter().fold(LEN_FIELD_SIZE, |acc, (name, value)| {
-                    acc + required_str_capacity(name) + required_str_capacity(value)
-                })
-            }
-        }) + size_of::<MsgPayloadVal>()
-    }
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! table {
+    ($port:expr, [ $headers:expr, $rows:expr $(,)? ]) => {};
+    ($headers:expr, $rows:expr $(,)?) => {};
 }
 
-// *** Message ***
+// *** backtrace macro ***
 
-#[doc(hidden)]
-#[derive(Clone, Debug)]
-pub struct Message(Vec<u8>);
+/// Send a backtrace of the current thread to the remote viewer (at [Level::Debug])
+///
+/// ```dontrun
+/// // Default port
+/// rdbg::backtrace!();
+///
+/// // Custom port
+/// let debug = rdbg::port(5000);
+/// rdbg::backtrace!(&debug);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! backtrace {
+    ($port:expr) => {
+        if $crate::level_enabled($crate::Level::Debug) {
+            $port.send_message(
+                $crate::__callsite!(),
+                $crate::Level::Debug,
+                $crate::MsgPayload::Backtrace($crate::backtrace_frames()),
+            );
+        }
+    };
 
-impl Message {
-    pub fn new(filename: &str, line: u32, payload: MsgPayload) -> Self {
-        let time = current_time();
-        let thread_id = current_thread();
+    () => {
+        $crate::backtrace!($crate::RemoteDebug::default_ref())
+    };
+}
 
-        // Msg length + time + thread id + filename len + line # + payload len
-        let len = LEN_FIELD_SIZE // msg len
-            + size_of::<u64>() // time
-            + required_str_capacity(&thread_id)
-            + required_str_capacity(filename)
-            + size_of::<u32>() // line #
-            + payload.required_capacity();
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! backtrace {
+    ($port:expr) => {};
+    () => {};
+}
 
-        let mut msg = Self(Vec::with_capacity(len));
-        msg.write_u32(len as u32);
-        msg.write_u64(time);
-        msg.write_str(&thread_id);
-        msg.write_str(filename);
-        msg.write_u32(line);
-        msg.write_payload(&payload);
+// *** Timing macros ***
 
-        debug_assert_eq!(msg.0.len(), len, "Bad message length");
-        msg
-    }
+/// Evaluate an expression and send how long it took to the remote viewer (at [Level::Debug]).
+/// The value of the expression is returned.
+///
+/// ```dontrun
+/// // Default port
+/// let sum = rdbg::time!("sum", (0..1000).sum::<u64>());
+///
+/// // Custom port
+/// let debug = rdbg::port(5000);
+/// let sum = rdbg::time!(&debug, ["sum", (0..1000).sum::<u64>()]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! time {
+    ($port:expr, [ $label:expr, $value:expr $(,)? ]) => {{
+        let _timer = $crate::timer!($port, [$label]);
+        $value
+    }};
 
-    #[inline]
-    pub fn as_slice(&self) -> &[u8] {
-        self.0.as_slice()
-    }
+    ($label:expr, $value:expr $(,)?) => {
+        $crate::time!($crate::RemoteDebug::default_ref(), [$label, $value])
+    };
+}
 
-    #[inline]
-    fn write_str(&mut self, s: &str) {
-        self.write_u32(s.len() as u32);
-        self.0.extend(s.as_bytes());
-    }
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! time {
+    ($port:expr, [ $label:expr, $value:expr $(,)? ]) => {
+        $value
+    };
+    ($label:expr, $value:expr $(,)?) => {
+        $value
+    };
+}
 
-    #[inline]
-    fn write_u8(&mut self, i: u8) {
-        self.0.extend(i.to_be_bytes());
-    }
+/// Start a timer that sends how long it was alive to the remote viewer when dropped (at
+/// [Level::Debug])
+///
+/// ```dontrun
+/// // Default port
+/// let _timer = rdbg::timer!("request");
+///
+/// // Custom port
+/// let debug = rdbg::port(5000);
+/// let _timer = rdbg::timer!(&debug, ["request"]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! timer {
+    (@new $port:expr, $label:expr) => {
+        $crate::level_enabled($crate::Level::Debug)
+            .then(|| $crate::Timer::new(&$port, $crate::__callsite!(), $label))
+    };
 
-    #[inline]
-    fn write_u32(&mut self, i: u32) {
-        self.0.extend(i.to_be_bytes());
-    }
+    ($port:expr, [ $label:literal $(,)? ]) => {
+        $crate::timer!(@new $port, ::std::borrow::Cow::Borrowed($label))
+    };
 
-    #[inline]
-    fn write_u64(&mut self, i: u64) {
-        self.0.extend(i.to_be_bytes());
-    }
+    ($port:expr, [ $label:expr $(,)? ]) => {
+        $crate::timer!(@new $port, ::std::borrow::Cow::Owned(($label).into()))
+    };
 
-    fn write_payload(&mut self, payload: &MsgPayload) {
-        match payload {
-            MsgPayload::Message(msg) => {
-                self.write_u8(MsgPayloadVal::Message as u8);
-                self.write_str(msg);
-            }
-            MsgPayload::Values(values) => {
-                self.write_u8(MsgPayloadVal::Values as u8);
-                self.write_u32(values.len() as u32);
+    ($label:literal) => {
+        $crate::timer!($crate::RemoteDebug::default_ref(), [$label])
+    };
 
-                for (name, value) in values {
-                    self.write_str(name);
-                    self.write_str(value);
-                }
-            }
-        }
-    }
+    ($label:expr) => {
+        $crate::timer!($crate::RemoteDebug::default_ref(), [$label])
+    };
 }
 
-// *** Event ***
-
-enum Event {
-    NewMessage(Message),
-    Flush,
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! timer {
+    ($port:expr, [ $label:expr $(,)? ]) => {
+        ()
+    };
+    ($label:expr) => {
+        ()
+    };
 }
 
-// *** Flushed ***
+// *** Metric macros ***
 
-#[derive(Clone)]
-struct Flushed(Arc<(Condvar, Mutex<bool>)>);
+/// Add `delta` to a named counter in the remote viewer (at [Level::Debug]). `delta` can be any
+/// type that converts losslessly into an `i64`, so wider types (ex: `u64` or `usize`) must be
+/// converted explicitly.
+///
+/// ```dontrun
+/// // Default port
+/// rdbg::counter!("requests", 1);
+///
+/// // Custom port
+/// let debug = rdbg::port(5000);
+/// rdbg::counter!(&debug, ["bytes_read", i64::try_from(buf.len()).unwrap()]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! counter {
+    (@send $port:expr, $name:expr, $delta:expr) => {
+        if $crate::level_enabled($crate::Level::Debug) {
+            $port.send_message(
+                $crate::__callsite!(),
+                $crate::Level::Debug,
+                $crate::MsgPayload::Counter {
+                    name: $name,
+                    delta: i64::from($delta),
+                },
+            );
+        }
+    };
 
-impl Flushed {
-    #[inline]
-    fn new() -> Self {
-        Self(Arc::new((Default::default(), Mutex::new(false))))
-    }
+    ($port:expr, [ $name:literal, $delta:expr $(,)? ]) => {
+        $crate::counter!(@send $port, ::std::borrow::Cow::Borrowed($name), $delta)
+    };
 
-    fn flushed(&self) {
-        let (var, lock) = &*self.0;
-        // Panic if mutex is poisoned
-        let mut flushed = lock.lock().unwrap();
-        *flushed = true;
-        var.notify_one();
-    }
+    ($port:expr, [ $name:expr, $delta:expr $(,)? ]) => {
+        $crate::counter!(@send $port, ::std::borrow::Cow::Owned(($name).into()), $delta)
+    };
 
-    fn flush_and_wait(&self, sender: &SyncSender<Event>) {
-        match sender.send(Event::Flush) {
-            Ok(_) => {
-                let (var, lock) = &*self.0;
-                // Panic if mutex is poisoned
-                let mut flushed = lock.lock().unwrap();
+    ($name:literal, $delta:expr $(,)?) => {
+        $crate::counter!($crate::RemoteDebug::default_ref(), [$name, $delta])
+    };
 
-                while !*flushed {
-                    // Panic if mutex is poisoned
-                    flushed = var.wait(flushed).unwrap();
+    ($name:expr, $delta:expr $(,)?) => {
+        $crate::counter!($crate::RemoteDebug::default_ref(), [$name, $delta])
+    };
+}
+
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! counter {
+    ($port:expr, [ $name:expr, $delta:expr $(,)? ]) => {};
+    ($name:expr, $delta:expr $(,)?) => {};
+}
+
+/// Set a named gauge to `value` in the remote viewer (at [Level::Debug]). `value` can be any type
+/// that converts losslessly into an `f64`, so wider types (ex: `u64` or `usize`) must be
+/// converted explicitly.
+///
+/// ```dontrun
+/// // Default port
+/// rdbg::gauge!("queue_len", queue.len() as f64);
+///
+/// // Custom port
+/// let debug = rdbg::port(5000);
+/// rdbg::gauge!(&debug, ["temperature", 21.5]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! gauge {
+    (@send $port:expr, $name:expr, $value:expr) => {
+        if $crate::level_enabled($crate::Level::Debug) {
+            $port.send_message(
+                $crate::__callsite!(),
+                $crate::Level::Debug,
+                $crate::MsgPayload::Gauge {
+                    name: $name,
+                    value: f64::from($value),
+                },
+            );
+        }
+    };
+
+    ($port:expr, [ $name:literal, $value:expr $(,)? ]) => {
+        $crate::gauge!(@send $port, ::std::borrow::Cow::Borrowed($name), $value)
+    };
+
+    ($port:expr, [ $name:expr, $value:expr $(,)? ]) => {
+        $crate::gauge!(@send $port, ::std::borrow::Cow::Owned(($name).into()), $value)
+    };
+
+    ($name:literal, $value:expr $(,)?) => {
+        $crate::gauge!($crate::RemoteDebug::default_ref(), [$name, $value])
+    };
+
+    ($name:expr, $value:expr $(,)?) => {
+        $crate::gauge!($crate::RemoteDebug::default_ref(), [$name, $value])
+    };
+}
+
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! gauge {
+    ($port:expr, [ $name:expr, $value:expr $(,)? ]) => {};
+    ($name:expr, $value:expr $(,)?) => {};
+}
+
+// *** Level macros ***
+
+/// Send a message to the remote viewer at [Level::Error]
+///
+/// ```dontrun
+/// rdbg::error!("Failed to open {}", path);
+///
+/// let debug = rdbg::port(5000);
+/// rdbg::error!(&debug, ["Failed to open {}", path]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! error {
+    ($port:expr, [ $($arg:tt)* ]) => {
+        $crate::__msg!($crate::Level::Error, $port, [ $($arg)* ])
+    };
+
+    ($($arg:tt)*) => {
+        $crate::error!($crate::RemoteDebug::default_ref(), [ $($arg)* ])
+    };
+}
+
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! error {
+    ($port:expr, [ $($arg:tt)* ]) => {};
+    ($($arg:tt)*) => {};
+}
+
+/// Send a message to the remote viewer at [Level::Warn]
+///
+/// ```dontrun
+/// rdbg::warn!("Retrying in {}ms", delay);
+///
+/// let debug = rdbg::port(5000);
+/// rdbg::warn!(&debug, ["Retrying in {}ms", delay]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! warn {
+    ($port:expr, [ $($arg:tt)* ]) => {
+        $crate::__msg!($crate::Level::Warn, $port, [ $($arg)* ])
+    };
+
+    ($($arg:tt)*) => {
+        $crate::warn!($crate::RemoteDebug::default_ref(), [ $($arg)* ])
+    };
+}
+
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! warn {
+    ($port:expr, [ $($arg:tt)* ]) => {};
+    ($($arg:tt)*) => {};
+}
+
+/// Send a message to the remote viewer at [Level::Info] (same as [msg!])
+///
+/// ```dontrun
+/// rdbg::info!("Connected to {}", host);
+///
+/// let debug = rdbg::port(5000);
+/// rdbg::info!(&debug, ["Connected to {}", host]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! info {
+    ($port:expr, [ $($arg:tt)* ]) => {
+        $crate::__msg!($crate::Level::Info, $port, [ $($arg)* ])
+    };
+
+    ($($arg:tt)*) => {
+        $crate::info!($crate::RemoteDebug::default_ref(), [ $($arg)* ])
+    };
+}
+
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! info {
+    ($port:expr, [ $($arg:tt)* ]) => {};
+    ($($arg:tt)*) => {};
+}
+
+/// Send a message to the remote viewer at [Level::Debug]
+///
+/// ```dontrun
+/// rdbg::debug!("Cache has {} entries", cache.len());
+///
+/// let debug = rdbg::port(5000);
+/// rdbg::debug!(&debug, ["Cache has {} entries", cache.len()]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! debug {
+    ($port:expr, [ $($arg:tt)* ]) => {
+        $crate::__msg!($crate::Level::Debug, $port, [ $($arg)* ])
+    };
+
+    ($($arg:tt)*) => {
+        $crate::debug!($crate::RemoteDebug::default_ref(), [ $($arg)* ])
+    };
+}
+
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! debug {
+    ($port:expr, [ $($arg:tt)* ]) => {};
+    ($($arg:tt)*) => {};
+}
+
+/// Send a message to the remote viewer at [Level::Trace]
+///
+/// ```dontrun
+/// rdbg::trace!("Entering parse_header");
+///
+/// let debug = rdbg::port(5000);
+/// rdbg::trace!(&debug, ["Entering parse_header"]);
+/// ```
+#[cfg(feature = "enabled")]
+#[macro_export]
+macro_rules! trace {
+    ($port:expr, [ $($arg:tt)* ]) => {
+        $crate::__msg!($crate::Level::Trace, $port, [ $($arg)* ])
+    };
+
+    ($($arg:tt)*) => {
+        $crate::trace!($crate::RemoteDebug::default_ref(), [ $($arg)* ])
+    };
+}
+
+#[cfg(not(feature = "enabled"))]
+#[macro_export]
+macro_rules! trace {
+    ($port:expr, [ $($arg:tt)* ]) => {};
+    ($($arg:tt)*) => {};
+}
+
+// *** Level ***
+
+/// The severity of a message. Ordered from most to least severe, so `Level::Error` is the
+/// smallest value.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+// The most verbose level that will be compiled in, as chosen by the `max_level_*` features
+// (the most restrictive wins if more than one is enabled)
+const STATIC_MAX_LEVEL: Option<Level> = if cfg!(feature = "max_level_off") {
+    None
+} else if cfg!(feature = "max_level_error") {
+    Some(Level::Error)
+} else if cfg!(feature = "max_level_warn") {
+    Some(Level::Warn)
+} else if cfg!(feature = "max_level_info") {
+    Some(Level::Info)
+} else if cfg!(feature = "max_level_debug") {
+    Some(Level::Debug)
+} else {
+    Some(Level::Trace)
+};
+
+/// Returns true if messages at this level are compiled in (see the `max_level_*` features)
+#[inline(always)]
+pub const fn level_enabled(level: Level) -> bool {
+    match STATIC_MAX_LEVEL {
+        Some(max) => level as u8 <= max as u8,
+        None => false,
+    }
+}
+
+// *** Message related functions ***
+
+fn thread_id(thread: &Thread) -> String {
+    // This has to be made into a string as there doesn't seem to be a way to get any
+    // sort of integral version out of it (at least not in stable)
+    parse_thread_id(format!("{:?}", thread.id()))
+}
+
+fn parse_thread_id(thread_id: String) -> String {
+    // We optimistically assume the current format, but if it isn't just return
+    // the initial string as-is
+    let mut split = thread_id.split(&['(', ')']);
+    if split.next() == Some(THREAD_ID_PREFIX) {
+        match split.next() {
+            Some(thread_id) if split.next() == Some("") => thread_id.to_string(),
+            _ => thread_id,
+        }
+    } else {
+        thread_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::ring::Ring;
+    use crate::{
+        level_enabled, parse_backtrace, parse_thread_id, Batch, Client, ClientEvent,
+        ClientReceiver, Config, Endpoint, Event, Flushed, Frame, History, HistoryBuffer, Input,
+        Level, Listener, Message, MsgPayload, Policy, Queue, Stream, BATCH_MAX_BYTES, BIND_ADDR,
+        CLIENT_MAX_MESSAGES, DEFAULT_PORT, QUEUE_MAX_MESSAGES, STATIC_MAX_LEVEL,
+    };
+    #[cfg(unix)]
+    use crate::{socket_path, SOCKET_PERMISSIONS};
+
+    #[test]
+    fn parse_thread_current() {
+        assert_eq!(parse_thread_id("ThreadId(1)".to_string()), "1".to_string());
+    }
+
+    #[test]
+    fn parse_thread_changed() {
+        assert_eq!(
+            parse_thread_id("Thread(1)".to_string()),
+            "Thread(1)".to_string()
+        );
+    }
+
+    fn config(vars: &[(&str, &str)]) -> Config {
+        Config::from_lookup(|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, val)| val.to_string())
+        })
+    }
+
+    #[test]
+    fn config_disabled() {
+        assert!(!config(&[]).disabled);
+        assert!(!config(&[("RDBG_DISABLE", "0")]).disabled);
+        assert!(!config(&[("RDBG_DISABLE", "")]).disabled);
+        assert!(config(&[("RDBG_DISABLE", "1")]).disabled);
+    }
+
+    #[test]
+    fn config_endpoint() {
+        assert_eq!(
+            config(&[("RDBG_ADDR", "0.0.0.0"), ("RDBG_PORT", "5000")]).default_endpoint,
+            Endpoint::Tcp("0.0.0.0:5000".parse::<SocketAddr>().unwrap())
+        );
+        // Invalid values are ignored
+        assert_eq!(
+            config(&[("RDBG_PORT", "bad")]).default_endpoint,
+            Endpoint::Tcp(SocketAddr::new(BIND_ADDR.parse().unwrap(), DEFAULT_PORT))
+        );
+        assert_eq!(
+            config(&[("RDBG_PORT", "5000"), ("RDBG_CONNECT", "host:5001")]).default_endpoint,
+            Endpoint::ConnectTcp("host:5001".to_string())
+        );
+    }
+
+    fn fill_queue(policy: Policy) -> (Queue, Vec<Arc<Message>>) {
+        let queue = Queue::new(policy);
+        let msgs: Vec<_> = (0..=QUEUE_MAX_MESSAGES)
+            .map(|i| {
+                Arc::new(Message::new(
+                    __callsite!(),
+                    Level::Info,
+                    None,
+                    MsgPayload::Message(i.to_string().into()),
+                ))
+            })
+            .collect();
+
+        for msg in &msgs {
+            queue.push(Event::NewMessage(msg.clone()));
+        }
+        (queue, msgs)
+    }
+
+    #[test]
+    fn queue_policies() {
+        let expect_next = |queue: &Queue, expected: &Arc<Message>| {
+            assert!(matches!(queue.next(false), Input::Dropped(1)));
+            match queue.next(false) {
+                Input::Event(Event::NewMessage(msg)) => assert!(Arc::ptr_eq(&msg, expected)),
+                _ => panic!("Expected a message"),
+            }
+        };
+
+        let (queue, msgs) = fill_queue(Policy::DropNewest);
+        expect_next(&queue, &msgs[0]);
+
+        let (queue, msgs) = fill_queue(Policy::DropOldest);
+        expect_next(&queue, &msgs[1]);
+
+        let (queue, msgs) = fill_queue(Policy::Timeout(Duration::from_millis(1)));
+        expect_next(&queue, &msgs[0]);
+    }
+
+    // A viewer whose queue is full and whose writer thread isn't running
+    fn full_client() -> (Client, ClientReceiver, Vec<Arc<Message>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = Stream::Tcp(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let (mut client, receiver) = Client::new(&stream).unwrap();
+
+        let msgs: Vec<_> = (0..=CLIENT_MAX_MESSAGES)
+            .map(|i| {
+                Arc::new(Message::new(
+                    __callsite!(),
+                    Level::Info,
+                    None,
+                    MsgPayload::Message(i.to_string().into()),
+                ))
+            })
+            .collect();
+        for msg in &msgs[..CLIENT_MAX_MESSAGES] {
+            assert!(client.send(ClientEvent::NewMessage(msg.clone()), Policy::Block));
+        }
+        (client, receiver, msgs)
+    }
+
+    fn next_message(receiver: &ClientReceiver) -> Arc<Message> {
+        match receiver.try_recv() {
+            Some(ClientEvent::NewMessage(msg)) => msg,
+            _ => panic!("Expected a message"),
+        }
+    }
+
+    #[test]
+    fn client_policies() {
+        // Blocks until the viewer makes room rather than lose the message
+        let (mut client, receiver, msgs) = full_client();
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            next_message(&receiver);
+            receiver
+        });
+        let last = ClientEvent::NewMessage(msgs[CLIENT_MAX_MESSAGES].clone());
+        assert!(client.send(last, Policy::Block));
+        let receiver = reader.join().unwrap();
+        assert_eq!(0, client.dropped);
+        assert!(Arc::ptr_eq(&msgs[1], &next_message(&receiver)));
+
+        // The newest message is skipped and reported to the viewer before the next one
+        let (mut client, receiver, msgs) = full_client();
+        let last = ClientEvent::NewMessage(msgs[CLIENT_MAX_MESSAGES].clone());
+        assert!(client.send(last, Policy::DropNewest));
+        assert_eq!(1, client.dropped);
+        assert!(Arc::ptr_eq(&msgs[0], &next_message(&receiver)));
+
+        // The oldest message is skipped to make room, and reported right away
+        let (mut client, receiver, msgs) = full_client();
+        let last = ClientEvent::NewMessage(msgs[CLIENT_MAX_MESSAGES].clone());
+        assert!(client.send(last, Policy::DropOldest));
+        assert_eq!(0, client.dropped);
+        let queued: Vec<_> = iter::from_fn(|| receiver.try_recv()).collect();
+        assert_eq!(CLIENT_MAX_MESSAGES + 1, queued.len());
+        match (&queued[0], &queued[CLIENT_MAX_MESSAGES]) {
+            (ClientEvent::NewMessage(first), ClientEvent::NewMessage(last)) => {
+                assert!(Arc::ptr_eq(&msgs[1], first));
+                assert!(Arc::ptr_eq(&msgs[CLIENT_MAX_MESSAGES], last));
+            }
+            _ => panic!("Expected messages"),
+        }
+
+        // A viewer that stops writing altogether is disconnected instead of waited on forever
+        let (mut client, _receiver, msgs) = full_client();
+        let last = ClientEvent::NewMessage(msgs[CLIENT_MAX_MESSAGES].clone());
+        assert!(!client.send(last, Policy::Block));
+    }
+
+    #[test]
+    fn client_unsent() {
+        let (mut client, receiver, msgs) = full_client();
+        next_message(&receiver);
+        receiver.unsent(vec![msgs[0].clone()]);
+        drop(receiver);
+
+        // Once the writer thread is gone, nothing more is queued and what it never wrote is kept
+        let last = ClientEvent::NewMessage(msgs[CLIENT_MAX_MESSAGES].clone());
+        assert!(!client.send(last, Policy::Block));
+        let unsent = client.take_unsent();
+        assert_eq!(CLIENT_MAX_MESSAGES, unsent.len());
+        assert!(unsent
+            .iter()
+            .zip(&msgs)
+            .all(|(unsent, msg)| Arc::ptr_eq(unsent, msg)));
+    }
+
+    #[test]
+    fn ring_push_pop() {
+        let ring = Ring::new(4);
+        assert!(ring.is_empty());
+
+        for i in 0..4 {
+            assert_eq!(Ok(()), ring.push(i));
+        }
+        assert!(ring.is_full());
+        assert_eq!(Err(4), ring.push(4));
+
+        // Wrap around to the next lap
+        assert_eq!(Some(0), ring.pop());
+        assert_eq!(Ok(()), ring.push(4));
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            iter::from_fn(|| ring.pop()).collect::<Vec<_>>()
+        );
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn ring_concurrent() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 4;
+        const PER_PRODUCER: usize = 10_000;
+
+        let ring = Ring::new(16);
+        let popped = AtomicUsize::new(0);
+
+        let received: Vec<Vec<(usize, usize)>> = thread::scope(|scope| {
+            for producer in 0..PRODUCERS {
+                let ring = &ring;
+                scope.spawn(move || {
+                    for i in 0..PER_PRODUCER {
+                        let mut value = (producer, i);
+                        while let Err(full) = ring.push(value) {
+                            value = full;
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+
+            let consumers: Vec<_> = (0..CONSUMERS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut values = Vec::new();
+                        while popped.load(Ordering::Relaxed) < PRODUCERS * PER_PRODUCER {
+                            match ring.pop() {
+                                Some(value) => {
+                                    values.push(value);
+                                    popped.fetch_add(1, Ordering::Relaxed);
+                                }
+                                None => thread::yield_now(),
+                            }
+                        }
+                        values
+                    })
+                })
+                .collect();
+
+            consumers
+                .into_iter()
+                .map(|consumer| consumer.join().unwrap())
+                .collect()
+        });
+
+        // Each consumer sees a producer's values in the order they were pushed
+        for values in &received {
+            for producer in 0..PRODUCERS {
+                let order: Vec<_> = values
+                    .iter()
+                    .filter(|(from, _)| *from == producer)
+                    .map(|(_, i)| *i)
+                    .collect();
+                assert!(order.windows(2).all(|pair| pair[0] < pair[1]));
+            }
+        }
+
+        // And every value was popped exactly once
+        let mut all: Vec<_> = received.into_iter().flatten().collect();
+        all.sort_unstable();
+        let expected: Vec<_> = (0..PRODUCERS)
+            .flat_map(|producer| (0..PER_PRODUCER).map(move |i| (producer, i)))
+            .collect();
+        assert_eq!(expected, all);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn queue_merges_threads() {
+        let queue = Arc::new(Queue::new(Policy::Block));
+        let senders: Vec<_> = (0..4)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..QUEUE_MAX_MESSAGES {
+                        queue.push(Event::NewMessage(Arc::new(Message::new(
+                            __callsite!(),
+                            Level::Info,
+                            None,
+                            MsgPayload::Message(i.to_string().into()),
+                        ))));
+                    }
+                })
+            })
+            .collect();
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        queue.push(Event::Flush(1));
+
+        // Every message comes before the flush, in the order it was sent
+        let mut seqs = Vec::new();
+        loop {
+            match queue.next(false) {
+                Input::Event(Event::NewMessage(msg)) => seqs.push(msg.seq),
+                Input::Event(Event::Flush(_)) => break,
+                _ => panic!("Expected a message or flush"),
+            }
+        }
+        assert_eq!(4 * QUEUE_MAX_MESSAGES, seqs.len());
+        assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn stale_flush() {
+        let queue = Arc::new(Queue::new(Policy::Block));
+        let flush = Flushed::new();
+        let done = Arc::new(AtomicBool::new(false));
+
+        // Nothing is dispatching, so this gives up and leaves its flush queued
+        flush.flush_and_wait_timeout(&queue, Duration::from_millis(10));
+
+        let dispatcher = {
+            let (queue, flush, done) = (queue.clone(), flush.clone(), done.clone());
+            thread::spawn(move || {
+                for expected in 1..=2 {
+                    match queue.next(false) {
+                        Input::Event(Event::Flush(generation)) => {
+                            assert_eq!(expected, generation)
+                        }
+                        _ => panic!("Expected a flush"),
+                    }
+
+                    // Give a waiter woken by the stale flush time to (wrongly) return
+                    if expected == 1 {
+                        flush.flushed(expected);
+                        thread::sleep(Duration::from_millis(50));
+                    } else {
+                        done.store(true, Ordering::SeqCst);
+                        flush.flushed(expected);
+                    }
+                }
+            })
+        };
+
+        flush.flush_and_wait(&queue);
+        assert!(done.load(Ordering::SeqCst));
+        dispatcher.join().unwrap();
+    }
+
+    #[test]
+    fn history_limits() {
+        let msg = Arc::new(Message::new(
+            __callsite!(),
+            Level::Info,
+            None,
+            MsgPayload::Message("msg".into()),
+        ));
+        let mut history = HistoryBuffer::new(History::Messages(2));
+
+        for _ in 0..3 {
+            history.push(&msg);
+        }
+        assert_eq!(2, history.messages().len());
+
+        history.set_limit(History::Bytes(msg.as_slice().len()));
+        assert_eq!(1, history.messages().len());
+
+        history.set_limit(History::Disabled);
+        history.push(&msg);
+        assert!(history.messages().is_empty());
+    }
+
+    #[test]
+    fn batch_messages() {
+        let msg = Arc::new(Message::new(
+            __callsite!(),
+            Level::Info,
+            None,
+            MsgPayload::Message("msg".into()),
+        ));
+        let callsite = msg.callsite_slice().unwrap();
+        let mut batch = Batch::new(Default::default());
+        assert!(!batch.is_due());
+
+        // The callsite definition only precedes the first message
+        batch.push(msg.clone());
+        batch.push(msg.clone());
+        assert_eq!(
+            callsite.len() + 2 * msg.as_slice().len(),
+            batch.buffer.len()
+        );
+        assert_eq!(callsite, &batch.buffer[..callsite.len()]);
+
+        while batch.buffer.len() < BATCH_MAX_BYTES {
+            batch.push(msg.clone());
+        }
+        assert!(batch.is_due());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_listener() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = socket_path(&format!("listener-test-{}", std::process::id()));
+        let listener = Listener::bind(&Endpoint::Unix(path.clone())).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(SOCKET_PERMISSIONS, mode & 0o777);
+
+        // A socket someone is still listening on is left alone
+        let err = Listener::bind(&Endpoint::Unix(path.clone())).err().unwrap();
+        assert_eq!(std::io::ErrorKind::AddrInUse, err.kind());
+
+        // Each bind uses its own temporary directory, so several in one directory don't clash
+        let other = path.with_file_name(format!("listener-test-{}-other", std::process::id()));
+        let other_listener = Listener::bind(&Endpoint::Unix(other.clone())).unwrap();
+
+        drop(listener);
+        drop(other_listener);
+        assert!(!path.exists());
+        assert!(!other.exists());
+
+        // A socket left behind by a process that is gone is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        drop(Listener::bind(&Endpoint::Unix(path.clone())).unwrap());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!(Ok(Policy::DropOldest), "drop-oldest".parse());
+        assert_eq!(
+            Ok(Policy::Timeout(Duration::from_millis(50))),
+            "timeout:50".parse()
+        );
+        assert_eq!(Err(()), "timeout:".parse::<Policy>());
+    }
+
+    #[test]
+    fn parse_backtrace_frames() {
+        let backtrace = "   0: my_crate::my_func
+             at ./src/main.rs:10:5
+             my_crate::inlined
+             at C:\\src\\inlined.rs:3:1
+   1: <unknown>
+";
+        let frame = |function: &str, file: &str, line| Frame {
+            function: function.to_string(),
+            file: file.to_string(),
+            line,
+        };
+
+        assert_eq!(
+            vec![
+                frame("my_crate::my_func", "./src/main.rs", 10),
+                frame("my_crate::inlined", "C:\\src\\inlined.rs", 3),
+                frame("<unknown>", "", 0),
+            ],
+            parse_backtrace(backtrace)
+        );
+    }
+
+    #[test]
+    fn level_filter() {
+        assert!(Level::Error < Level::Trace);
+
+        for level in [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ] {
+            let expected = matches!(STATIC_MAX_LEVEL, Some(max) if level <= max);
+            assert_eq!(expected, level_enabled(level));
+        }
+
+        // Without any max_level_* features everything is compiled in
+        #[cfg(not(any(
+            feature = "max_level_off",
+            feature = "max_level_error",
+            feature = "max_level_warn",
+            feature = "max_level_info",
+            feature = "max_level_debug"
+        )))]
+        assert_eq!(Some(Level::Trace), STATIC_MAX_LEVEL);
+    }
+
+    // Every macro must still compile wherever it is normally used when disabled
+    #[cfg(not(feature = "enabled"))]
+    #[test]
+    #[allow(clippy::let_unit_value)]
+    fn disabled_macros() {
+        let _tag = crate::scoped_tag("tag");
+        let _timer = crate::timer!("timer");
+        let _timer = crate::timer!(crate::port(5000), ["timer"]);
+        assert_eq!(42, crate::time!("time", 42));
+
+        crate::msg!("Hello {}", "world");
+        crate::msgf!("Hello {}", "world");
+        crate::vals!(1 + 1, "world");
+        crate::valsf!(1 + 1, "world");
+        crate::bytes!("bytes", [1u8, 2, 3]);
+        crate::table!(["a"], [["1"]]);
+        crate::backtrace!();
+        crate::counter!("counter", 1);
+        crate::gauge!("gauge", 0.5);
+        crate::error!("error");
+        crate::warn!("warn");
+        crate::info!("info");
+        crate::debug!("debug");
+        crate::trace!("trace");
+        crate::flush();
+    }
+}
+
+fn current_time_ns() -> u64 {
+    // u64 nanoseconds since the epoch won't overflow until the year 2554
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+// The wall clock time (in nanoseconds since the epoch) and instant that monotonic times are
+// relative to. This is fixed the first time it is needed (no later than when the first port is
+// opened)
+fn process_start() -> &'static (u64, Instant) {
+    static START: OnceLock<(u64, Instant)> = OnceLock::new();
+    START.get_or_init(|| (current_time_ns(), Instant::now()))
+}
+
+#[inline]
+fn next_sequence() -> u64 {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    // We only need each number to be unique, not any ordering with other memory
+    SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+fn new_session_id() -> u128 {
+    // We have no dependencies, so we rely on the randomly seeded hasher std uses for `HashMap`
+    // (mixed with the time and PID for good measure)
+    let random = || {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(current_time_ns());
+        hasher.write_u32(process::id());
+        hasher.finish() as u128
+    };
+    let id = (random() << 64) | random();
+
+    // Mark it as a version 4 (random) UUID
+    (id & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62)
+}
+
+// The hello record is the same for every viewer of every port, so it is built once (the first
+// time a port is opened)
+fn hello() -> &'static Message {
+    static HELLO: OnceLock<Message> = OnceLock::new();
+    HELLO.get_or_init(Message::hello)
+}
+
+#[inline]
+fn required_str_capacity(s: &str) -> usize {
+    s.len() + LEN_FIELD_SIZE
+}
+
+// *** MsgPayloadVal ***
+
+#[repr(u8)]
+enum MsgPayloadVal {
+    Message = 1,
+    Values = 2,
+    Dropped = 3,
+    HistoryEnd = 4,
+    SpanNew = 5,
+    SpanEnter = 6,
+    SpanExit = 7,
+    SpanClose = 8,
+    SpanRecord = 9,
+    Event = 10,
+    Panic = 11,
+    Backtrace = 12,
+    Timing = 13,
+    Counter = 14,
+    Gauge = 15,
+    StructuredValues = 16,
+    Bytes = 17,
+    Table = 18,
+}
+
+// *** MsgPayload ***
+
+// Borrowed text (message text, labels and names) is static, so it is sent once per connection in
+// the callsite record rather than with every message. It must be the same for every message sent
+// from a callsite, which the macros guarantee by only borrowing literals.
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub enum MsgPayload {
+    // A formatted string
+    Message(Cow<'static, str>),
+    // The values of the expressions named by the callsite
+    Values(Vec<String>),
+    // The number of messages dropped due to a full queue (sent by rdbg itself)
+    Dropped(u64),
+    // Marks the end of replayed history and the start of live messages (sent by rdbg itself)
+    HistoryEnd(u64),
+    // A new tracing span (a parent of 0 means it is a root span)
+    SpanNew {
+        id: u64,
+        parent: u64,
+        name: &'static str,
+        fields: Vec<(&'static str, String)>,
+    },
+    // A tracing span was entered
+    SpanEnter(u64),
+    // A tracing span was exited
+    SpanExit(u64),
+    // A tracing span was closed (its ID may be reused afterwards)
+    SpanClose(u64),
+    // New field values were recorded for a tracing span
+    SpanRecord {
+        id: u64,
+        fields: Vec<(&'static str, String)>,
+    },
+    // A tracing event within a span (a span of 0 means it isn't within one)
+    Event {
+        span: u64,
+        fields: Vec<(&'static str, String)>,
+    },
+    // A panic (sent by the panic hook)
+    Panic {
+        message: String,
+        thread: String,
+        backtrace: Vec<Frame>,
+    },
+    // The stack of the calling thread, innermost frame first
+    Backtrace(Vec<Frame>),
+    // How long something took (start is in nanoseconds since the epoch)
+    Timing {
+        label: Cow<'static, str>,
+        start_ns: u64,
+        duration_ns: u64,
+    },
+    // An amount to add to a named counter
+    Counter {
+        name: Cow<'static, str>,
+        delta: i64,
+    },
+    // The current value of a named gauge
+    Gauge {
+        name: Cow<'static, str>,
+        value: f64,
+    },
+    // The values of the expressions named by the callsite, each already serialized (see
+    // `encode_value`)
+    StructuredValues(Vec<Vec<u8>>),
+    // A labeled binary buffer
+    Bytes {
+        label: Cow<'static, str>,
+        data: Vec<u8>,
+    },
+    // Column headers and rows of cells (rows may have a different number of cells than headers)
+    Table {
+        headers: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
+}
+
+impl MsgPayload {
+    // Static text is only borrowed from the format string when there is nothing to format
+    #[doc(hidden)]
+    pub fn message(args: Arguments<'_>) -> Self {
+        MsgPayload::Message(match args.as_str() {
+            Some(text) => Cow::Borrowed(text),
+            None => Cow::Owned(fmt::format(args)),
+        })
+    }
+
+    // The static text sent in the callsite record (empty if there is none)
+    fn static_text(&self) -> &'static str {
+        match self {
+            MsgPayload::Message(Cow::Borrowed(text))
+            | MsgPayload::Timing {
+                label: Cow::Borrowed(text),
+                ..
+            }
+            | MsgPayload::Counter {
+                name: Cow::Borrowed(text),
+                ..
+            }
+            | MsgPayload::Gauge {
+                name: Cow::Borrowed(text),
+                ..
+            }
+            | MsgPayload::Bytes {
+                label: Cow::Borrowed(text),
+                ..
+            } => text,
+            _ => "",
+        }
+    }
+
+    fn required_capacity(&self) -> usize {
+        (match self {
+            MsgPayload::Message(msg) => required_text_capacity(msg),
+            MsgPayload::Values(values) => required_row_capacity(values),
+            MsgPayload::Dropped(_) | MsgPayload::HistoryEnd(_) => size_of::<u64>(),
+            MsgPayload::SpanNew { name, fields, .. } => {
+                size_of::<u64>() * 2
+                    + required_str_capacity(name)
+                    + required_values_capacity(fields)
+            }
+            MsgPayload::SpanEnter(_) | MsgPayload::SpanExit(_) | MsgPayload::SpanClose(_) => {
+                size_of::<u64>()
+            }
+            MsgPayload::SpanRecord { fields, .. } | MsgPayload::Event { fields, .. } => {
+                size_of::<u64>() + required_values_capacity(fields)
+            }
+            MsgPayload::Panic {
+                message,
+                thread,
+                backtrace,
+            } => {
+                required_str_capacity(message)
+                    + required_str_capacity(thread)
+                    + required_frames_capacity(backtrace)
+            }
+            MsgPayload::Backtrace(frames) => required_frames_capacity(frames),
+            MsgPayload::Timing { label, .. } => {
+                required_text_capacity(label) + size_of::<u64>() * 2
+            }
+            MsgPayload::Counter { name, .. } => required_text_capacity(name) + size_of::<i64>(),
+            MsgPayload::Gauge { name, .. } => required_text_capacity(name) + size_of::<f64>(),
+            MsgPayload::StructuredValues(values) => {
+                //  We start with 4 because we start by sending number of vec elements
+                values
+                    .iter()
+                    .fold(LEN_FIELD_SIZE, |acc, value| acc + value.len())
+            }
+            MsgPayload::Bytes { label, data } => {
+                required_text_capacity(label) + LEN_FIELD_SIZE + data.len()
+            }
+            MsgPayload::Table { headers, rows } => rows.iter().fold(
+                required_row_capacity(headers) + LEN_FIELD_SIZE,
+                |acc, row| acc + required_row_capacity(row),
+            ),
+        }) + size_of::<MsgPayloadVal>()
+    }
+}
+
+fn required_frames_capacity(frames: &[Frame]) -> usize {
+    //  We start with 4 because we start by sending number of vec elements
+    frames.iter().fold(LEN_FIELD_SIZE, |acc, frame| {
+        acc + required_str_capacity(&frame.function)
+            + required_str_capacity(&frame.file)
+            + size_of::<u32>() // line #
+    })
+}
+
+#[inline]
+fn required_values_capacity(values: &[(&'static str, String)]) -> usize {
+    //  We start with 4 because we start by sending number of vec elements
+    values.iter().fold(LEN_FIELD_SIZE, |acc, (name, value)| {
+        acc + required_str_capacity(name) + required_str_capacity(value)
+    })
+}
+
+#[inline]
+fn required_row_capacity(row: &[impl AsRef<str>]) -> usize {
+    //  We start with 4 because we start by sending number of vec elements
+    row.iter().fold(LEN_FIELD_SIZE, |acc, cell| {
+        acc + required_str_capacity(cell.as_ref())
+    })
+}
+
+// Borrowed text is sent in the callsite record instead, leaving an empty string in its place
+// (the lint is wrong here - whether the text is borrowed is the point)
+#[allow(clippy::ptr_arg)]
+#[inline]
+fn required_text_capacity(text: &Cow<'static, str>) -> usize {
+    match text {
+        Cow::Borrowed(_) => LEN_FIELD_SIZE,
+        Cow::Owned(text) => required_str_capacity(text),
+    }
+}
+
+// *** Table ***
+
+#[doc(hidden)]
+pub fn table_row<I>(cells: I) -> Vec<String>
+where
+    I: IntoIterator,
+    I::Item: ToString,
+{
+    cells.into_iter().map(|cell| cell.to_string()).collect()
+}
+
+// *** Frame ***
+
+#[doc(hidden)]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Frame {
+    function: String,
+    // Empty (with a line of 0) if the location is unknown
+    file: String,
+    line: u32,
+}
+
+#[doc(hidden)]
+#[inline(never)]
+pub fn backtrace_frames() -> Vec<Frame> {
+    // The first frame is this function, which is of no interest
+    capture_frames("rdbg::backtrace_frames")
+}
+
+// Captures a backtrace of the current thread, dropping all frames up to and including the first
+// one whose function contains `skip_through` (if any)
+fn capture_frames(skip_through: &str) -> Vec<Frame> {
+    let mut frames = parse_backtrace(&Backtrace::force_capture().to_string());
+
+    if let Some(idx) = frames
+        .iter()
+        .position(|frame| frame.function.contains(skip_through))
+    {
+        frames.drain(..=idx);
+    }
+    frames
+}
+
+// There is no stable way to access the frames of a backtrace, so we parse its display output:
+//    0: my_crate::my_func
+//              at ./src/main.rs:10:5
+fn parse_backtrace(backtrace: &str) -> Vec<Frame> {
+    let mut frames: Vec<Frame> = Vec::new();
+
+    for line in backtrace
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        match line.strip_prefix("at ") {
+            Some(location) => {
+                // File names may contain ':' (ex: Windows drives), so we split from the right
+                let mut parts = location.rsplitn(3, ':').skip(1); // Skip column
+                let (line_no, file) = (parts.next(), parts.next());
+
+                if let (Some(frame), Some(Ok(line_no)), Some(file)) =
+                    (frames.last_mut(), line_no.map(str::parse), file)
+                {
+                    frame.file = file.to_string();
+                    frame.line = line_no;
+                }
+            }
+            None => {
+                // Inlined functions are listed without a frame number
+                let function = match line.split_once(": ") {
+                    Some((num, function)) if num.bytes().all(|b| b.is_ascii_digit()) => function,
+                    _ => line,
+                };
+
+                frames.push(Frame {
+                    function: function.to_string(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    frames
+}
+
+// *** Callsite ***
+
+/// Where messages are sent from. Created by the macros (one `static` per use), or at runtime for
+/// locations that are only known then (see [Callsite::dynamic]).
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct Callsite {
+    filename: &'static str,
+    line: u32,
+    // Zero if unknown
+    column: u32,
+    // Empty if unknown
+    module_path: &'static str,
+    // The expressions whose values are sent from here, in order (see `vals!`)
+    names: &'static [&'static str],
+    // Set the first time a message is sent from here
+    record: OnceLock<CallsiteRecord>,
+}
+
+impl Callsite {
+    pub const fn new(
+        filename: &'static str,
+        line: u32,
+        column: u32,
+        module_path: &'static str,
+        names: &'static [&'static str],
+    ) -> Self {
+        Self {
+            filename,
+            line,
+            column,
+            module_path,
+            names,
+            record: OnceLock::new(),
+        }
+    }
+
+    /// Returns the callsite for a location only known at runtime (ex: of a `log` record). Each
+    /// one (and the strings it refers to) is leaked the first time it is seen, but there are only
+    /// so many in a program. Messages with different text can share one, so they must own
+    /// their text.
+    pub fn dynamic(filename: &str, line: u32, column: u32, module_path: &str) -> &'static Callsite {
+        type Callsites = HashMap<Location<'static>, &'static Callsite>;
+        static CALLSITES: OnceLock<RwLock<Callsites>> = OnceLock::new();
+
+        let location = Location {
+            filename,
+            line,
+            column,
+            module_path,
+        };
+        let callsites = CALLSITES.get_or_init(Default::default);
+        let lookup = |callsites: &HashMap<Location<'_>, _>| callsites.get(&location).copied();
+
+        // Panic if lock is poisoned
+        if let Some(callsite) = lookup(&callsites.read().unwrap()) {
+            return callsite;
+        }
+
+        // Panic if lock is poisoned
+        let mut callsites = callsites.write().unwrap();
+        // Another thread may have added it while we were waiting for the lock
+        if let Some(callsite) = lookup(&callsites) {
+            return callsite;
+        }
+
+        let location = Location {
+            filename: Box::leak(filename.into()),
+            module_path: Box::leak(module_path.into()),
+            ..location
+        };
+        let callsite: &'static Callsite = Box::leak(Box::new(Callsite::new(
+            location.filename,
+            line,
+            column,
+            location.module_path,
+            &[],
+        )));
+        callsites.insert(location, callsite);
+        callsite
+    }
+
+    // Assigns this callsite a process wide ID the first time a message is sent from it. `text` is
+    // the static text of that message (see `MsgPayload`).
+    #[inline]
+    fn register(&'static self, text: &'static str) -> &'static CallsiteRecord {
+        self.record.get_or_init(|| {
+            static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+            CallsiteRecord {
+                id,
+                record: Message::callsite(id, self, text),
+            }
+        })
+    }
+}
+
+// *** Location ***
+
+// Identifies a dynamic callsite
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+struct Location<'a> {
+    filename: &'a str,
+    line: u32,
+    column: u32,
+    module_path: &'a str,
+}
+
+// *** CallsiteRecord ***
+
+// A callsite's process wide ID, along with the record that defines it for viewers
+#[derive(Debug)]
+struct CallsiteRecord {
+    id: u32,
+    record: Message,
+}
+
+// *** Timer ***
+
+/// Guard returned by [timer] that sends how long it was alive when dropped
+#[must_use = "the timer stops as soon as this is dropped"]
+pub struct Timer {
+    debug: RemoteDebug,
+    callsite: &'static Callsite,
+    label: Cow<'static, str>,
+    start_ns: u64,
+    started: Instant,
+}
+
+impl Timer {
+    #[doc(hidden)]
+    pub fn new(debug: &RemoteDebug, callsite: &'static Callsite, label: Cow<'static, str>) -> Self {
+        Self {
+            debug: debug.clone(),
+            callsite,
+            label,
+            start_ns: current_time_ns(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let duration_ns = self.started.elapsed().as_nanos() as u64;
+
+        self.debug.send_message(
+            self.callsite,
+            Level::Debug,
+            MsgPayload::Timing {
+                label: mem::take(&mut self.label),
+                start_ns: self.start_ns,
+                duration_ns,
+            },
+        );
+    }
+}
+
+// *** Message ***
+
+#[repr(u8)]
+enum RecordKind {
+    Message = 1,
+    Callsite = 2,
+}
+
+#[doc(hidden)]
+#[derive(Clone, Debug)]
+pub struct Message {
+    bytes: Vec<u8>,
+    // The callsite this message refers to (`None` for records that aren't messages)
+    callsite: Option<&'static CallsiteRecord>,
+    // Used to merge messages from each thread's buffer back into the order they were sent
+    seq: u64,
+}
+
+impl Message {
+    #[inline]
+    fn with_capacity(len: usize, callsite: Option<&'static CallsiteRecord>) -> Self {
+        Self {
+            bytes: Vec::with_capacity(len),
+            callsite,
+            seq: 0,
+        }
+    }
+
+    pub fn new(
+        callsite: &'static Callsite,
+        level: Level,
+        tag: Option<&str>,
+        payload: MsgPayload,
+    ) -> Self {
+        let callsite = callsite.register(payload.static_text());
+        let time_ns = current_time_ns();
+        let mono_ns = process_start().1.elapsed().as_nanos() as u64;
+        let seq = next_sequence();
+        let thread = thread::current();
+        let thread_id = thread_id(&thread);
+        // An empty name means the thread is unnamed
+        let thread_name = thread.name().unwrap_or_default();
+
+        // An empty tag means the message has no tag
+        let tag = tag.unwrap_or_default();
+
+        // Msg length + kind + time + monotonic time + sequence # + thread id + thread name +
+        // callsite ID + level + tag + payload len
+        let len = LEN_FIELD_SIZE // msg len
+            + size_of::<RecordKind>()
+            + size_of::<u64>() // time
+            + size_of::<u64>() // monotonic time
+            + size_of::<u64>() // sequence #
+            + required_str_capacity(&thread_id)
+            + required_str_capacity(thread_name)
+            + size_of::<u32>() // callsite ID
+            + size_of::<Level>()
+            + required_str_capacity(tag)
+            + payload.required_capacity();
+
+        let mut msg = Self::with_capacity(len, Some(callsite));
+        msg.seq = seq;
+        msg.write_u32(len as u32);
+        msg.write_u8(RecordKind::Message as u8);
+        msg.write_u64(time_ns);
+        msg.write_u64(mono_ns);
+        msg.write_u64(seq);
+        msg.write_str(&thread_id);
+        msg.write_str(thread_name);
+        msg.write_u32(callsite.id);
+        msg.write_u8(level as u8);
+        msg.write_str(tag);
+        msg.write_payload(&payload);
+
+        debug_assert_eq!(msg.bytes.len(), len, "Bad message length");
+        msg
+    }
+
+    // The record sent to each viewer right after the protocol version, describing this program
+    fn hello() -> Self {
+        let pid = process::id();
+        // Both of these are best effort - neither is guaranteed to be meaningful
+        let exe = env::current_exe()
+            .map(|exe| exe.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let args: Vec<_> = env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let start_time_ns = process_start().0;
+        let session_id = new_session_id();
+
+        // Msg length + pid + exe + args + crate name + crate version + start time + session ID
+        let len = LEN_FIELD_SIZE // msg len
+            + size_of::<u32>() // pid
+            + required_str_capacity(&exe)
+            + required_row_capacity(&args)
+            + required_str_capacity(CRATE_NAME)
+            + required_str_capacity(CRATE_VERSION)
+            + size_of::<u64>() // start time
+            + size_of::<u128>(); // session ID
+
+        let mut msg = Self::with_capacity(len, None);
+        msg.write_u32(len as u32);
+        msg.write_u32(pid);
+        msg.write_str(&exe);
+        msg.write_row(&args);
+        msg.write_str(CRATE_NAME);
+        msg.write_str(CRATE_VERSION);
+        msg.write_u64(start_time_ns);
+        msg.bytes.extend(session_id.to_be_bytes());
+
+        debug_assert_eq!(msg.bytes.len(), len, "Bad message length");
+        msg
+    }
+
+    // The record that defines a callsite for viewers, which is sent before the first message
+    // that refers to it
+    fn callsite(id: u32, callsite: &Callsite, text: &str) -> Self {
+        // Msg length + kind + callsite ID + filename + line # + column # + module path + text +
+        // names
+        let len = LEN_FIELD_SIZE // msg len
+            + size_of::<RecordKind>()
+            + size_of::<u32>() // callsite ID
+            + required_str_capacity(callsite.filename)
+            + size_of::<u32>() // line #
+            + size_of::<u32>() // column #
+            + required_str_capacity(callsite.module_path)
+            + required_str_capacity(text)
+            + required_row_capacity(callsite.names);
+
+        let mut msg = Self::with_capacity(len, None);
+        msg.write_u32(len as u32);
+        msg.write_u8(RecordKind::Callsite as u8);
+        msg.write_u32(id);
+        msg.write_str(callsite.filename);
+        msg.write_u32(callsite.line);
+        msg.write_u32(callsite.column);
+        msg.write_str(callsite.module_path);
+        msg.write_str(text);
+        msg.write_row(callsite.names);
+
+        debug_assert_eq!(msg.bytes.len(), len, "Bad message length");
+        msg
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+
+    /// The record defining the callsite of this message (if it is a message)
+    #[inline]
+    pub fn callsite_slice(&self) -> Option<&[u8]> {
+        self.callsite.map(|callsite| callsite.record.as_slice())
+    }
+
+    #[inline]
+    fn write_str(&mut self, s: &str) {
+        self.write_u32(s.len() as u32);
+        self.bytes.extend(s.as_bytes());
+    }
+
+    #[inline]
+    fn write_u8(&mut self, i: u8) {
+        self.bytes.extend(i.to_be_bytes());
+    }
+
+    #[inline]
+    fn write_u32(&mut self, i: u32) {
+        self.bytes.extend(i.to_be_bytes());
+    }
+
+    #[inline]
+    fn write_u64(&mut self, i: u64) {
+        self.bytes.extend(i.to_be_bytes());
+    }
+
+    fn write_payload(&mut self, payload: &MsgPayload) {
+        match payload {
+            MsgPayload::Message(msg) => {
+                self.write_u8(MsgPayloadVal::Message as u8);
+                self.write_text(msg);
+            }
+            MsgPayload::Values(values) => {
+                self.write_u8(MsgPayloadVal::Values as u8);
+                self.write_row(values);
+            }
+            MsgPayload::Dropped(count) => {
+                self.write_u8(MsgPayloadVal::Dropped as u8);
+                self.write_u64(*count);
+            }
+            MsgPayload::HistoryEnd(count) => {
+                self.write_u8(MsgPayloadVal::HistoryEnd as u8);
+                self.write_u64(*count);
+            }
+            MsgPayload::SpanNew {
+                id,
+                parent,
+                name,
+                fields,
+            } => {
+                self.write_u8(MsgPayloadVal::SpanNew as u8);
+                self.write_u64(*id);
+                self.write_u64(*parent);
+                self.write_str(name);
+                self.write_values(fields);
+            }
+            MsgPayload::SpanEnter(id) => {
+                self.write_u8(MsgPayloadVal::SpanEnter as u8);
+                self.write_u64(*id);
+            }
+            MsgPayload::SpanExit(id) => {
+                self.write_u8(MsgPayloadVal::SpanExit as u8);
+                self.write_u64(*id);
+            }
+            MsgPayload::SpanClose(id) => {
+                self.write_u8(MsgPayloadVal::SpanClose as u8);
+                self.write_u64(*id);
+            }
+            MsgPayload::SpanRecord { id, fields } => {
+                self.write_u8(MsgPayloadVal::SpanRecord as u8);
+                self.write_u64(*id);
+                self.write_values(fields);
+            }
+            MsgPayload::Event { span, fields } => {
+                self.write_u8(MsgPayloadVal::Event as u8);
+                self.write_u64(*span);
+                self.write_values(fields);
+            }
+            MsgPayload::Panic {
+                message,
+                thread,
+                backtrace,
+            } => {
+                self.write_u8(MsgPayloadVal::Panic as u8);
+                self.write_str(message);
+                self.write_str(thread);
+                self.write_frames(backtrace);
+            }
+            MsgPayload::Backtrace(frames) => {
+                self.write_u8(MsgPayloadVal::Backtrace as u8);
+                self.write_frames(frames);
+            }
+            MsgPayload::Timing {
+                label,
+                start_ns,
+                duration_ns,
+            } => {
+                self.write_u8(MsgPayloadVal::Timing as u8);
+                self.write_text(label);
+                self.write_u64(*start_ns);
+                self.write_u64(*duration_ns);
+            }
+            MsgPayload::Counter { name, delta } => {
+                self.write_u8(MsgPayloadVal::Counter as u8);
+                self.write_text(name);
+                self.write_u64(*delta as u64);
+            }
+            MsgPayload::Gauge { name, value } => {
+                self.write_u8(MsgPayloadVal::Gauge as u8);
+                self.write_text(name);
+                self.write_u64(value.to_bits());
+            }
+            MsgPayload::StructuredValues(values) => {
+                self.write_u8(MsgPayloadVal::StructuredValues as u8);
+                self.write_u32(values.len() as u32);
+
+                // Encoded values are self-delimiting, so they are written as is
+                for value in values {
+                    self.bytes.extend(value);
+                }
+            }
+            MsgPayload::Bytes { label, data } => {
+                self.write_u8(MsgPayloadVal::Bytes as u8);
+                self.write_text(label);
+                self.write_u32(data.len() as u32);
+                self.bytes.extend(data);
+            }
+            MsgPayload::Table { headers, rows } => {
+                self.write_u8(MsgPayloadVal::Table as u8);
+                self.write_row(headers);
+                self.write_u32(rows.len() as u32);
+
+                for row in rows {
+                    self.write_row(row);
+                }
+            }
+        }
+    }
+
+    fn write_frames(&mut self, frames: &[Frame]) {
+        self.write_u32(frames.len() as u32);
+
+        for frame in frames {
+            self.write_str(&frame.function);
+            self.write_str(&frame.file);
+            self.write_u32(frame.line);
+        }
+    }
+
+    fn write_row(&mut self, row: &[impl AsRef<str>]) {
+        self.write_u32(row.len() as u32);
+
+        for cell in row {
+            self.write_str(cell.as_ref());
+        }
+    }
+
+    // Borrowed text is in the callsite record, so only an empty string is written in its place
+    #[allow(clippy::ptr_arg)]
+    #[inline]
+    fn write_text(&mut self, text: &Cow<'static, str>) {
+        match text {
+            Cow::Borrowed(_) => self.write_str(""),
+            Cow::Owned(text) => self.write_str(text),
+        }
+    }
+
+    fn write_values(&mut self, values: &[(&'static str, String)]) {
+        self.write_u32(values.len() as u32);
+
+        for (name, value) in values {
+            self.write_str(name);
+            self.write_str(value);
+        }
+    }
+}
+
+// *** Event ***
+
+enum Event {
+    NewMessage(Arc<Message>),
+    // Carries the generation of the flush so waiters can tell theirs apart
+    Flush(u64),
+}
+
+// *** Policy ***
+
+/// What to do when a message is sent but the queue is full (typically because no viewer is
+/// attached or it can't keep up). Each viewer also has its own queue, and the same policy applies
+/// when one of those is full, except that a viewer that stops reading altogether is disconnected.
+///
+/// This can also be set at runtime via the `RDBG_POLICY` environment variable using one of
+/// `block`, `drop-newest`, `drop-oldest` or `timeout:<milliseconds>`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Policy {
+    /// Wait until there is room in the queue (the default)
+    #[default]
+    Block,
+    /// Discard the message being sent
+    DropNewest,
+    /// Discard the oldest message queued by the sending thread to make room
+    DropOldest,
+    /// Wait up to the given duration for room, then discard the message being sent
+    Timeout(Duration),
+}
+
+impl FromStr for Policy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Policy::Block),
+            "drop-newest" => Ok(Policy::DropNewest),
+            "drop-oldest" => Ok(Policy::DropOldest),
+            _ => match s.strip_prefix("timeout:") {
+                Some(millis) => Ok(Policy::Timeout(Duration::from_millis(
+                    millis.parse().map_err(|_| ())?,
+                ))),
+                None => Err(()),
+            },
+        }
+    }
+}
+
+// *** History ***
+
+/// How much recent history to keep so it can be replayed to newly connected viewers. Replayed
+/// messages are followed by a marker so the viewer knows where live messages begin.
+///
+/// This can also be set at runtime via the `RDBG_HISTORY` environment variable using one of
+/// `off`, `messages:<count>` or `bytes:<size>`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum History {
+    /// Don't keep any history (the default)
+    #[default]
+    Disabled,
+    /// Keep up to this many of the most recent messages
+    Messages(usize),
+    /// Keep as many of the most recent messages as fit in this many bytes
+    Bytes(usize),
+}
+
+impl FromStr for History {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "off" {
+            Ok(History::Disabled)
+        } else if let Some(count) = s.strip_prefix("messages:") {
+            Ok(History::Messages(count.parse().map_err(|_| ())?))
+        } else if let Some(size) = s.strip_prefix("bytes:") {
+            Ok(History::Bytes(size.parse().map_err(|_| ())?))
+        } else {
+            Err(())
+        }
+    }
+}
+
+// *** HistoryBuffer ***
+
+struct HistoryBuffer {
+    limit: History,
+    msgs: VecDeque<Arc<Message>>,
+    bytes: usize,
+}
+
+impl HistoryBuffer {
+    #[inline]
+    fn new(limit: History) -> Self {
+        Self {
+            limit,
+            msgs: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    #[inline]
+    fn is_enabled(&self) -> bool {
+        self.limit != History::Disabled
+    }
+
+    fn set_limit(&mut self, limit: History) {
+        self.limit = limit;
+        self.trim();
+    }
+
+    fn push(&mut self, msg: &Arc<Message>) {
+        if self.is_enabled() {
+            self.bytes += msg.as_slice().len();
+            self.msgs.push_back(msg.clone());
+            self.trim();
+        }
+    }
+
+    fn trim(&mut self) {
+        while match self.limit {
+            History::Disabled => !self.msgs.is_empty(),
+            History::Messages(count) => self.msgs.len() > count,
+            History::Bytes(size) => self.bytes > size,
+        } {
+            // Can't fail - it isn't possible to be over the limit with no messages
+            let msg = self.msgs.pop_front().unwrap();
+            self.bytes -= msg.as_slice().len();
+        }
+    }
+
+    #[inline]
+    fn messages(&self) -> Vec<Arc<Message>> {
+        self.msgs.iter().cloned().collect()
+    }
+}
+
+// *** Queue ***
+
+enum Input {
+    Client(Client),
+    SetHistory(History),
+    SetPolicy(Policy),
+    Dropped(u64),
+    Event(Event),
+}
+
+// Each thread sending to a queue appends its messages to its own buffer so senders never
+// contend with each other. The dispatcher collects them, merged back into the order they were
+// sent.
+type Buffer = Ring<Arc<Message>>;
+
+thread_local! {
+    // This thread's buffer for each queue it has sent to, by queue ID
+    static BUFFERS: RefCell<Vec<(u64, Arc<Buffer>)>> = const { RefCell::new(Vec::new()) };
+}
+
+struct QueueState {
+    // Events other than messages (these are never dropped and never wait)
+    events: VecDeque<Event>,
+    // Events collected and ordered by the dispatcher, but not yet taken by it
+    ready: VecDeque<Event>,
+    // The buffer of every thread that has sent to this queue (that may still have messages)
+    buffers: Vec<Arc<Buffer>>,
+    // Newly connected viewers - these are handed to the sender thread before any events
+    clients: VecDeque<Client>,
+    // Like clients, a history change takes effect immediately rather than in order
+    history: Option<History>,
+    policy: Policy,
+    // Set when the policy changes, since the dispatcher applies it to slow viewers too
+    policy_changed: bool,
+    // Messages dropped since the last time they were reported
+    dropped: u64,
+    // Senders waiting for room in their buffer
+    blocked: usize,
+}
+
+struct Queue {
+    // Identifies this queue's buffer in each thread's `BUFFERS`
+    id: u64,
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    // Set while the dispatcher is waiting for input, so senders know they need to wake it
+    sleeping: AtomicBool,
+}
+
+impl Queue {
+    fn new(policy: Policy) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            state: Mutex::new(QueueState {
+                events: VecDeque::new(),
+                ready: VecDeque::new(),
+                buffers: Vec::new(),
+                clients: VecDeque::new(),
+                history: None,
+                policy,
+                policy_changed: false,
+                dropped: 0,
+                blocked: 0,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            sleeping: AtomicBool::new(false),
+        }
+    }
+
+    #[inline]
+    fn push(&self, event: Event) {
+        self.push_within(event, None);
+    }
+
+    // Like push, but if `max_wait` is given, never waits longer than that for room regardless of
+    // the policy
+    fn push_within(&self, event: Event, max_wait: Option<Duration>) {
+        match event {
+            Event::NewMessage(msg) => self.push_message(msg, max_wait),
+            event => {
+                // Panic if mutex is poisoned
+                self.state.lock().unwrap().events.push_back(event);
+                self.not_empty.notify_one();
+            }
+        }
+    }
+
+    fn push_message(&self, msg: Arc<Message>, max_wait: Option<Duration>) {
+        let buffer = self.buffer();
+
+        // Fast path - no locks unless the dispatcher needs waking
+        let mut msg = match buffer.push(msg) {
+            Ok(()) => {
+                self.wake();
+                return;
+            }
+            Err(msg) => msg,
+        };
+
+        // Panic if mutex is poisoned
+        let mut state = self.state.lock().unwrap();
+
+        // Only messages are subject to the policy
+        loop {
+            // The dispatcher may have made room since we last tried
+            msg = match buffer.push(msg) {
+                Ok(()) => {
+                    drop(state);
+                    self.wake();
+                    return;
+                }
+                Err(msg) => msg,
+            };
+
+            let policy = match (state.policy, max_wait) {
+                (Policy::Block, Some(max_wait)) => Policy::Timeout(max_wait),
+                (Policy::Timeout(timeout), Some(max_wait)) => {
+                    Policy::Timeout(timeout.min(max_wait))
+                }
+                (policy, _) => policy,
+            };
+
+            match policy {
+                Policy::Block => {
+                    state.blocked += 1;
+                    // Panic if mutex is poisoned
+                    state = self.not_full.wait(state).unwrap();
+                    state.blocked -= 1;
+                }
+                Policy::DropNewest => {
+                    state.dropped += 1;
+                    return;
+                }
+                Policy::DropOldest => {
+                    // The dispatcher may have emptied the buffer first, in which case there is
+                    // now room anyway
+                    if buffer.pop().is_some() {
+                        state.dropped += 1;
+                    }
+                }
+                Policy::Timeout(timeout) => {
+                    state.blocked += 1;
+                    // Panic if mutex is poisoned
+                    state = self
+                        .not_full
+                        .wait_timeout_while(state, timeout, |_| buffer.is_full())
+                        .unwrap()
+                        .0;
+                    state.blocked -= 1;
+
+                    if buffer.is_full() {
+                        state.dropped += 1;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // Returns this thread's buffer for this queue, creating it on first use
+    fn buffer(&self) -> Arc<Buffer> {
+        BUFFERS
+            .try_with(|buffers| {
+                let mut buffers = buffers.borrow_mut();
+
+                match buffers.iter().find(|(id, _)| *id == self.id) {
+                    Some((_, buffer)) => buffer.clone(),
+                    None => {
+                        let buffer = self.new_buffer();
+                        buffers.push((self.id, buffer.clone()));
+                        buffer
+                    }
+                }
+            })
+            // This thread's buffers were already destroyed (ex: sending from the destructor of
+            // another thread local) so use a buffer just for this message instead. It is
+            // discarded once the dispatcher has collected from it.
+            .unwrap_or_else(|_| self.new_buffer())
+    }
+
+    fn new_buffer(&self) -> Arc<Buffer> {
+        let buffer = Arc::new(Ring::new(QUEUE_MAX_MESSAGES));
+        // Panic if mutex is poisoned
+        self.state.lock().unwrap().buffers.push(buffer.clone());
+        buffer
+    }
+
+    #[inline]
+    fn wake(&self) {
+        // Pairs with the fence in `next` - either it sees our message or we see it is sleeping
+        fence(Ordering::SeqCst);
+
+        if self.sleeping.load(Ordering::Relaxed) {
+            // Taking the lock ensures the dispatcher is either not yet waiting (and will see our
+            // message) or is waiting and will get the notification
+            // Panic if mutex is poisoned
+            let _state = self.state.lock().unwrap();
+            self.not_empty.notify_one();
+        }
+    }
+
+    fn push_client(&self, client: Client) {
+        // Panic if mutex is poisoned
+        self.state.lock().unwrap().clients.push_back(client);
+        self.not_empty.notify_one();
+    }
+
+    // If `hold_events` is set, only wait for new clients and leave events queued
+    fn next(&self, hold_events: bool) -> Input {
+        // Panic if mutex is poisoned
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(client) = state.clients.pop_front() {
+                return Input::Client(client);
+            }
+
+            if let Some(history) = state.history.take() {
+                return Input::SetHistory(history);
+            }
+
+            if mem::take(&mut state.policy_changed) {
+                return Input::SetPolicy(state.policy);
+            }
+
+            if !hold_events {
+                if state.dropped > 0 {
+                    return Input::Dropped(mem::take(&mut state.dropped));
+                }
+
+                if let Some(event) = state.ready.pop_front() {
+                    return Input::Event(event);
+                }
+
+                if let Some(event) = state.events.pop_front() {
+                    // Anything sent before this event must be dispatched before it, and since it
+                    // was queued after those messages were buffered, collecting now finds them all
+                    self.collect(&mut state);
+                    state.ready.push_back(event);
+                    continue;
+                }
+
+                if self.collect(&mut state) {
+                    continue;
+                }
+
+                self.sleeping.store(true, Ordering::Relaxed);
+                // Pairs with the fence in `wake` - either we see their message or they see we are
+                // sleeping
+                fence(Ordering::SeqCst);
+
+                if state.buffers.iter().any(|buffer| !buffer.is_empty()) {
+                    self.sleeping.store(false, Ordering::Relaxed);
+                    continue;
+                }
+            }
+
+            // Panic if mutex is poisoned
+            state = self.not_empty.wait(state).unwrap();
+            self.sleeping.store(false, Ordering::Relaxed);
+        }
+    }
+
+    // Moves all buffered messages into `ready` in the order they were sent, returning false if
+    // there were none
+    fn collect(&self, state: &mut QueueState) -> bool {
+        let mut msgs = Vec::new();
+
+        for buffer in &state.buffers {
+            // Bounded so a busy sender can't keep us here forever
+            msgs.extend(iter::from_fn(|| buffer.pop()).take(QUEUE_MAX_MESSAGES));
+        }
+
+        // Only the dispatcher holds a buffer once its thread has exited
+        state
+            .buffers
+            .retain(|buffer| Arc::strong_count(buffer) > 1 || !buffer.is_empty());
+
+        if msgs.is_empty() {
+            return false;
+        }
+
+        if state.blocked > 0 {
+            self.not_full.notify_all();
+        }
+
+        // Each buffer is already in order, but the buffers need merging
+        msgs.sort_by_key(|msg| msg.seq);
+        state.ready.extend(msgs.into_iter().map(Event::NewMessage));
+        true
+    }
+
+    #[inline]
+    fn set_history(&self, history: History) {
+        // Panic if mutex is poisoned
+        self.state.lock().unwrap().history = Some(history);
+        self.not_empty.notify_one();
+    }
+
+    #[inline]
+    fn set_policy(&self, policy: Policy) {
+        // Panic if mutex is poisoned
+        let mut state = self.state.lock().unwrap();
+        state.policy = policy;
+        state.policy_changed = true;
+        // Wake any blocked senders so they re-evaluate under the new policy
+        self.not_full.notify_all();
+        self.not_empty.notify_one();
+    }
+}
+
+// *** ClientEvent ***
+
+enum ClientEvent {
+    NewMessage(Arc<Message>),
+    // Recent history followed by the end of history marker
+    Replay(Vec<Arc<Message>>, Arc<Message>),
+    // The sender is never used - it is simply dropped once all prior messages have been written
+    Flush(Sender<()>),
+}
+
+impl ClientEvent {
+    // How many messages are lost if this is dropped
+    #[inline]
+    fn message_count(&self) -> u64 {
+        match self {
+            ClientEvent::NewMessage(_) => 1,
+            ClientEvent::Replay(msgs, _) => msgs.len() as u64,
+            ClientEvent::Flush(_) => 0,
+        }
+    }
+}
+
+// *** ClientQueue ***
+
+struct ClientQueueState {
+    events: VecDeque<ClientEvent>,
+    // Set once either the dispatcher or the writer thread is done with this viewer
+    closed: bool,
+}
+
+// The events waiting to be written to one viewer, shared by the dispatcher and that viewer's
+// writer thread
+struct ClientQueue {
+    state: Mutex<ClientQueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    // Only used to forcibly disconnect a viewer that stops reading
+    stream: Stream,
+    // Bytes written to the viewer so far, to tell a slow viewer from a stalled one
+    written: Arc<AtomicU64>,
+}
+
+impl ClientQueue {
+    // Waits for the viewer to write everything sent before `ack`, disconnecting it if it stops
+    // making progress
+    fn wait_for(&self, ack: &Receiver<()>) {
+        loop {
+            let written = self.written.load(Ordering::Relaxed);
+
+            match ack.recv_timeout(Duration::from_millis(CLIENT_WAIT_TIME)) {
+                Err(RecvTimeoutError::Timeout)
+                    if self.written.load(Ordering::Relaxed) == written =>
+                {
+                    self.disconnect();
+                    return;
+                }
+                // Still catching up
+                Err(RecvTimeoutError::Timeout) => {}
+                // The ack is dropped once caught up (or the viewer disconnected)
+                _ => return,
+            }
+        }
+    }
+
+    #[inline]
+    fn is_closed(&self) -> bool {
+        // Panic if mutex is poisoned
+        self.state.lock().unwrap().closed
+    }
+
+    #[inline]
+    fn disconnect(&self) {
+        let _ = self.stream.shutdown();
+    }
+}
+
+// *** Client ***
+
+// The dispatcher's end of a viewer
+struct Client {
+    queue: Arc<ClientQueue>,
+    // Messages skipped because this viewer's queue was full, not yet reported to it
+    dropped: u64,
+    // When this viewer's queue was found full, and how much it had written by then (if it still
+    // is full)
+    full_since: Option<(Instant, u64)>,
+}
+
+impl Client {
+    fn new(stream: &Stream) -> io::Result<(Self, ClientReceiver)> {
+        let queue = Arc::new(ClientQueue {
+            state: Mutex::new(ClientQueueState {
+                events: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            stream: stream.try_clone()?,
+            written: Arc::new(AtomicU64::new(0)),
+        });
+        let client = Self {
+            queue: queue.clone(),
+            dropped: 0,
+            full_since: None,
+        };
+        Ok((client, ClientReceiver { queue }))
+    }
+
+    // Returns false if this viewer is gone (or was disconnected for not reading). If its queue is
+    // full, `policy` decides whether to wait for room or drop a message, like it does for
+    // senders. Flushes are never dropped and never wait.
+    fn send(&mut self, event: ClientEvent, policy: Policy) -> bool {
+        let Self {
+            queue,
+            dropped,
+            full_since,
+        } = self;
+        // Panic if mutex is poisoned
+        let mut state = queue.state.lock().unwrap();
+        let is_flush = matches!(event, ClientEvent::Flush(_));
+
+        if !is_flush && state.events.len() >= CLIENT_MAX_MESSAGES {
+            let deadline = match policy {
+                Policy::Block => None,
+                Policy::Timeout(timeout) => Some(Instant::now() + timeout),
+                Policy::DropNewest | Policy::DropOldest => Some(Instant::now()),
+            };
+
+            loop {
+                if state.closed {
+                    return false;
+                }
+                if state.events.len() < CLIENT_MAX_MESSAGES {
+                    break;
+                }
+
+                // A viewer that is merely slow gets the policy, but one that hasn't written
+                // anything for a while is disconnected so it can't stall everyone else
+                let written = queue.written.load(Ordering::Relaxed);
+                match full_since {
+                    Some((since, last)) if *last == written => {
+                        if since.elapsed() >= Duration::from_millis(CLIENT_WAIT_TIME) {
+                            drop(state);
+                            queue.disconnect();
+                            return false;
+                        }
+                    }
+                    _ => *full_since = Some((Instant::now(), written)),
+                }
+
+                let wait = Duration::from_millis(CLIENT_WAIT_TIME);
+                let wait = match deadline {
+                    Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                        Some(remaining) if !remaining.is_zero() => remaining.min(wait),
+                        _ => break,
+                    },
+                    None => wait,
+                };
+                // Panic if mutex is poisoned
+                state = queue.not_full.wait_timeout(state, wait).unwrap().0;
+            }
+
+            if state.events.len() >= CLIENT_MAX_MESSAGES {
+                match policy {
+                    Policy::DropOldest => {
+                        // Flushes are never dropped, so skip over any queued
+                        if let Some(idx) = state
+                            .events
+                            .iter()
+                            .position(|event| !matches!(event, ClientEvent::Flush(_)))
+                        {
+                            // Can't fail - we just found it
+                            *dropped += state.events.remove(idx).unwrap().message_count();
+                        }
+                    }
+                    _ => {
+                        *dropped += event.message_count();
+                        return true;
+                    }
+                }
+            } else {
+                *full_since = None;
+            }
+        } else if state.closed {
+            return false;
+        } else if state.events.len() < CLIENT_MAX_MESSAGES {
+            *full_since = None;
+        }
+
+        // Let the viewer know what it missed before sending it anything else
+        if *dropped > 0 && !is_flush {
+            let msg = Message::new(
+                __callsite!(),
+                Level::Warn,
+                None,
+                MsgPayload::Dropped(mem::take(dropped)),
+            );
+            state
+                .events
+                .push_back(ClientEvent::NewMessage(Arc::new(msg)));
+        }
+
+        state.events.push_back(event);
+        queue.not_empty.notify_one();
+        true
+    }
+
+    // Takes the messages this viewer never got (ex: because it disconnected) so they can be sent
+    // to the next one instead
+    fn take_unsent(&self) -> Vec<Arc<Message>> {
+        // Panic if mutex is poisoned
+        let mut state = self.queue.state.lock().unwrap();
+
+        state
+            .events
+            .drain(..)
+            .flat_map(|event| match event {
+                ClientEvent::NewMessage(msg) => vec![msg],
+                ClientEvent::Replay(msgs, marker) => msgs.into_iter().chain([marker]).collect(),
+                ClientEvent::Flush(_) => vec![],
+            })
+            .collect()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Panic if mutex is poisoned
+        self.queue.state.lock().unwrap().closed = true;
+        // The writer thread still writes anything already queued before it stops
+        self.queue.not_empty.notify_one();
+    }
+}
+
+// The writer thread's end of a Client
+struct ClientReceiver {
+    queue: Arc<ClientQueue>,
+}
+
+impl ClientReceiver {
+    // Waits for the next event, returning None once the dispatcher is done with this viewer
+    fn recv(&self) -> Option<ClientEvent> {
+        // Panic if mutex is poisoned
+        let mut state = self.queue.state.lock().unwrap();
+
+        loop {
+            if let Some(event) = state.events.pop_front() {
+                self.queue.not_full.notify_one();
+                return Some(event);
+            }
+
+            if state.closed {
+                return None;
+            }
+
+            // Panic if mutex is poisoned
+            state = self.queue.not_empty.wait(state).unwrap();
+        }
+    }
+
+    fn try_recv(&self) -> Option<ClientEvent> {
+        // Panic if mutex is poisoned
+        let event = self.queue.state.lock().unwrap().events.pop_front();
+        if event.is_some() {
+            self.queue.not_full.notify_one();
+        }
+        event
+    }
+
+    // Puts back messages that couldn't be written, so the dispatcher can send them to the next
+    // viewer
+    fn unsent(&self, msgs: Vec<Arc<Message>>) {
+        // Panic if mutex is poisoned
+        let mut state = self.queue.state.lock().unwrap();
+
+        for msg in msgs.into_iter().rev() {
+            state.events.push_front(ClientEvent::NewMessage(msg));
+        }
+    }
+}
+
+impl Drop for ClientReceiver {
+    fn drop(&mut self) {
+        // Panic if mutex is poisoned
+        self.queue.state.lock().unwrap().closed = true;
+        // Don't leave the dispatcher waiting for room that will never come
+        self.queue.not_full.notify_all();
+    }
+}
+
+// *** Flushed ***
+
+#[derive(Default)]
+struct FlushState {
+    // Generation of the last flush queued
+    requested: u64,
+    // Generation of the last flush completed - completing one also completes all before it
+    completed: u64,
+}
+
+#[derive(Clone)]
+struct Flushed(Arc<(Condvar, Mutex<FlushState>)>);
+
+impl Flushed {
+    #[inline]
+    fn new() -> Self {
+        Self(Default::default())
+    }
+
+    fn flushed(&self, generation: u64) {
+        let (var, lock) = &*self.0;
+        // Panic if mutex is poisoned
+        let mut state = lock.lock().unwrap();
+        // A flush that was given up on may complete after a later one, so never go backwards
+        state.completed = state.completed.max(generation);
+        var.notify_all();
+    }
+
+    // Queues a flush, returning the lock so it can't complete before we start waiting
+    fn request<'a>(&'a self, queue: &Queue) -> (MutexGuard<'a, FlushState>, u64) {
+        let (_, lock) = &*self.0;
+        // Panic if mutex is poisoned
+        let mut state = lock.lock().unwrap();
+        state.requested += 1;
+        let generation = state.requested;
+        // Pushing while locked keeps generations in order in the queue (this never waits, as
+        // only messages are subject to the policy)
+        queue.push(Event::Flush(generation));
+        (state, generation)
+    }
+
+    fn flush_and_wait(&self, queue: &Queue) {
+        let (mut state, generation) = self.request(queue);
+        let (var, _) = &*self.0;
+
+        while state.completed < generation {
+            // Panic if mutex is poisoned
+            state = var.wait(state).unwrap();
+        }
+    }
+
+    // Like flush_and_wait, but gives up after `timeout` (ex: if no viewer is attached)
+    fn flush_and_wait_timeout(&self, queue: &Queue, timeout: Duration) {
+        let (state, generation) = self.request(queue);
+        let (var, _) = &*self.0;
+
+        // Panic if mutex is poisoned
+        let _ = var
+            .wait_timeout_while(state, timeout, |state| state.completed < generation)
+            .unwrap();
+    }
+}
+
+// *** Config ***
+
+struct Config {
+    disabled: bool,
+    // Only needed by `port`
+    #[cfg(feature = "enabled")]
+    bind_addr: IpAddr,
+    default_endpoint: Endpoint,
+    policy: Policy,
+    history: History,
+}
+
+impl Config {
+    #[inline]
+    fn from_env() -> Self {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        // Treat empty variables the same as missing ones
+        let lookup = |name| lookup(name).filter(|val| !val.is_empty());
+
+        let disabled = matches!(lookup(DISABLE_VAR), Some(val) if val != "0" && val != "false");
+        let bind_addr = match parse_var(ADDR_VAR, lookup(ADDR_VAR)) {
+            Some(addr) => addr,
+            // Can't fail - valid constant
+            None => BIND_ADDR.parse().unwrap(),
+        };
+        let port = parse_var(PORT_VAR, lookup(PORT_VAR)).unwrap_or(DEFAULT_PORT);
+        let policy = parse_var(POLICY_VAR, lookup(POLICY_VAR)).unwrap_or_default();
+        let history = parse_var(HISTORY_VAR, lookup(HISTORY_VAR)).unwrap_or_default();
+
+        let mut default_endpoint = Endpoint::Tcp(SocketAddr::new(bind_addr, port));
+        #[cfg(unix)]
+        if let Some(path) = lookup(UNIX_VAR) {
+            default_endpoint = Endpoint::Unix(PathBuf::from(path));
+        }
+        if let Some(addr) = lookup(CONNECT_VAR) {
+            default_endpoint = Endpoint::ConnectTcp(addr);
+        }
+
+        Self {
+            disabled,
+            #[cfg(feature = "enabled")]
+            bind_addr,
+            default_endpoint,
+            policy,
+            history,
+        }
+    }
+}
+
+fn parse_var<T: FromStr>(name: &str, val: Option<String>) -> Option<T> {
+    let val = val?;
+
+    match val.parse() {
+        Ok(val) => Some(val),
+        Err(_) => {
+            eprintln!("Ignoring invalid value for {name}: {val}");
+            None
+        }
+    }
+}
+
+// *** Registry ***
+
+struct Registry {
+    // Read from the environment on first use
+    config: Option<Config>,
+    // Each endpoint gets its own independent queue, listener thread and flush
+    remote_debugs: Vec<(Endpoint, RemoteDebug)>,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Self {
+            config: None,
+            remote_debugs: Vec::new(),
+        }
+    }
+
+    fn remote_debug(&mut self, endpoint: impl FnOnce(&Config) -> Endpoint) -> RemoteDebug {
+        let config = self.config.get_or_insert_with(Config::from_env);
+        if config.disabled {
+            return RemoteDebug::disabled();
+        }
+        let endpoint = endpoint(config);
+
+        // If this endpoint is already registered, just return it otherwise do one time thread creation
+        match self.remote_debugs.iter().find(|(e, _)| *e == endpoint) {
+            Some((_, remote_debug)) => remote_debug.clone(),
+            None => {
+                let debug = handle_connections(endpoint.clone(), config);
+                self.remote_debugs.push((endpoint, debug.clone()));
+                debug
+            }
+        }
+    }
+}
+
+// *** RemoteDebug ***
+
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct RemoteDebug {
+    // If this is None, rdbg was disabled at runtime and everything is a no-op
+    queue: Option<Arc<Queue>>,
+    flush: Flushed,
+    // If this is None, the thread's scoped tag (if any) is used instead
+    tag: Option<Arc<str>>,
+}
+
+impl RemoteDebug {
+    fn from_queue(queue: Arc<Queue>) -> Self {
+        Self {
+            queue: Some(queue),
+            flush: Flushed::new(),
+            tag: None,
+        }
+    }
+
+    fn disabled() -> Self {
+        Self {
+            queue: None,
+            flush: Flushed::new(),
+            tag: None,
+        }
+    }
+
+    fn from_endpoint(endpoint: impl FnOnce(&Config) -> Endpoint) -> Self {
+        // Panic if mutex is poisoned
+        REMOTE_DEBUG.lock().unwrap().remote_debug(endpoint)
+    }
+
+    // Like `default`, but without cloning (or locking the registry) on every message
+    pub fn default_ref() -> &'static Self {
+        static DEFAULT: OnceLock<RemoteDebug> = OnceLock::new();
+        DEFAULT.get_or_init(|| Self::from_endpoint(|config| config.default_endpoint.clone()))
+    }
+
+    pub fn send_message(&self, callsite: &'static Callsite, level: Level, payload: MsgPayload) {
+        match &self.tag {
+            Some(tag) => self.send_tagged_message(callsite, level, Some(tag), payload),
+            None => SCOPED_TAG.with(|tag| {
+                self.send_tagged_message(callsite, level, tag.borrow().as_deref(), payload)
+            }),
+        }
+    }
+
+    // Returns false if rdbg was disabled at runtime (so there is no point building messages)
+    #[cfg(feature = "tracing")]
+    #[inline]
+    fn is_active(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    // Like send_message, but the tag is given explicitly instead of coming from this instance
+    // or the scoped tag
+    fn send_tagged_message(
+        &self,
+        callsite: &'static Callsite,
+        level: Level,
+        tag: Option<&str>,
+        payload: MsgPayload,
+    ) {
+        if let Some(queue) = &self.queue {
+            queue.push(Event::NewMessage(Arc::new(Message::new(
+                callsite, level, tag, payload,
+            ))));
+        }
+    }
+
+    /// Returns a copy of this instance that tags every message it sends with `tag` (overriding
+    /// any scoped tag)
+    pub fn with_tag(&self, tag: &str) -> Self {
+        Self {
+            tag: Some(tag.into()),
+            ..self.clone()
+        }
+    }
+
+    #[inline]
+    pub fn flush(&self) {
+        if let Some(queue) = &self.queue {
+            self.flush.flush_and_wait(queue);
+        }
+    }
+
+    /// Install a panic hook that sends panics to this instance. See [install_panic_hook].
+    pub fn install_panic_hook(&self) {
+        let debug = self.clone();
+        let prev = panic::take_hook();
+
+        panic::set_hook(Box::new(move |info| {
+            debug.send_panic(info);
+            prev(info);
+        }));
+    }
+
+    #[allow(deprecated)]
+    fn send_panic(&self, info: &PanicInfo<'_>) {
+        if let Some(queue) = &self.queue {
+            let payload = info.payload();
+            let message = match payload.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => "Box<dyn Any>".to_string(),
+                },
+            };
+            let thread = thread::current().name().unwrap_or("<unnamed>").to_string();
+            // The module isn't known for panics
+            let callsite = match info.location() {
+                Some(location) => {
+                    Callsite::dynamic(location.file(), location.line(), location.column(), "")
                 }
+                None => Callsite::dynamic("", 0, 0, ""),
+            };
+
+            let msg = Message::new(
+                callsite,
+                Level::Error,
+                None,
+                MsgPayload::Panic {
+                    message,
+                    thread,
+                    // Skip the frames of the panic machinery itself (as the default hook does)
+                    backtrace: capture_frames("__rust_end_short_backtrace"),
+                },
+            );
+
+            // We are panicking, so we can't wait forever for room or for a viewer to attach
+            let max_wait = Duration::from_millis(PANIC_WAIT_TIME);
+            queue.push_within(Event::NewMessage(Arc::new(msg)), Some(max_wait));
+            self.flush.flush_and_wait_timeout(queue, max_wait);
+        }
+    }
+
+    /// Change what happens when a message is sent but the queue is full
+    #[inline]
+    pub fn set_policy(&self, policy: Policy) {
+        if let Some(queue) = &self.queue {
+            queue.set_policy(policy);
+        }
+    }
+
+    /// Change how much recent history is kept for newly connected viewers
+    #[inline]
+    pub fn set_history(&self, history: History) {
+        if let Some(queue) = &self.queue {
+            queue.set_history(history);
+        }
+    }
+}
+
+impl Default for RemoteDebug {
+    #[inline]
+    fn default() -> Self {
+        Self::default_ref().clone()
+    }
+}
+
+// NOTE: This function isn't a part of RemoteDebug simply to make it a few less key strokes for the
+// user in case they want to include this on every macro invocation.
+/// Specify a custom port for the TCP socket to listen on when using the [msg] and [vals] macros.
+///
+/// Each port is completely independent with its own queue and listener, so different parts of
+/// a program can be sent to different viewers. The listener for a port is started the first time
+/// it is used.
+///
+/// ```dontrun
+/// let world = "world!";
+/// rdbg::msg!(rdbg::port(5000), ["Hello {}", world]);
+///
+/// rdbg::vals!(rdbg::port(5001), [world, 1 + 1]);
+/// rdbg::port(5000).flush();
+/// rdbg::port(5001).flush();
+/// ```
+#[cfg(feature = "enabled")]
+#[inline]
+pub fn port(port: u16) -> RemoteDebug {
+    RemoteDebug::from_endpoint(|config| Endpoint::Tcp(SocketAddr::new(config.bind_addr, port)))
+}
+
+#[cfg(not(feature = "enabled"))]
+#[inline]
+pub fn port(_port: u16) {}
+
+/// Specify a Unix domain socket path to listen on when using the [msg] and [vals] macros. Any
+/// existing socket file at this path is replaced, and the new one is only accessible by the
+/// current user (this can be changed via regular file permissions after the first use).
+///
+/// Like [port], each path is completely independent with its own queue and listener. See
+/// [socket_path] for an easy way to create a path unique to a program.
+///
+/// ```dontrun
+/// let world = "world!";
+/// let debug = rdbg::unix(rdbg::socket_path(env!("CARGO_PKG_NAME")));
+/// rdbg::msg!(&debug, ["Hello {}", world]);
+///
+/// rdbg::vals!(&debug, [world, 1 + 1]);
+/// debug.flush();
+/// ```
+#[cfg(all(unix, feature = "enabled"))]
+#[inline]
+pub fn unix(path: impl AsRef<Path>) -> RemoteDebug {
+    RemoteDebug::from_endpoint(|_| Endpoint::Unix(path.as_ref().to_path_buf()))
+}
 
-                // Reset before releasing lock
-                *flushed = false;
-            }
-            Err(err) => {
-                eprintln!("Unable to send quit event: {err}");
-            }
-        }
-    }
+#[cfg(all(unix, not(feature = "enabled")))]
+#[inline]
+pub fn unix(_path: impl AsRef<Path>) {}
+
+/// Returns a Unix domain socket path in the temp directory based on `name`. This is useful
+/// to give each program (ex: via crate name) or process (ex: via PID) its own socket.
+///
+/// ```dontrun
+/// let by_crate = rdbg::socket_path(env!("CARGO_PKG_NAME"));
+/// let by_pid = rdbg::socket_path(&std::process::id().to_string());
+/// ```
+#[cfg(unix)]
+pub fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rdbg-{name}.sock"))
 }
 
-// *** RemoteDebug ***
+/// Specify a viewer address (`host:port`) to connect out to when using the [msg] and [vals]
+/// macros. This reverses the usual roles so the viewer listens and the debugged program
+/// connects, which works well for short-lived programs and for many programs sending to one
+/// viewer. If the viewer isn't available yet (or goes away), connecting is retried.
+///
+/// Like [port], each address is completely independent with its own queue and connection.
+///
+/// ```dontrun
+/// let world = "world!";
+/// let debug = rdbg::connect("127.0.0.1:13579");
+/// rdbg::msg!(&debug, ["Hello {}", world]);
+///
+/// rdbg::vals!(&debug, [world, 1 + 1]);
+/// debug.flush();
+/// ```
+#[cfg(feature = "enabled")]
+#[inline]
+pub fn connect(addr: &str) -> RemoteDebug {
+    RemoteDebug::from_endpoint(|_| Endpoint::ConnectTcp(addr.to_string()))
+}
 
-#[doc(hidden)]
-#[derive(Clone)]
-pub struct RemoteDebug {
-    sender: SyncSender<Event>,
-    flush: Flushed,
+#[cfg(not(feature = "enabled"))]
+#[inline]
+pub fn connect(_addr: &str) {}
+
+/// Specify a Unix domain socket path of a listening viewer to connect out to when using the
+/// [msg] and [vals] macros. See [connect] for more details.
+///
+/// ```dontrun
+/// let world = "world!";
+/// let debug = rdbg::connect_unix(rdbg::socket_path("viewer"));
+/// rdbg::msg!(&debug, ["Hello {}", world]);
+/// debug.flush();
+/// ```
+#[cfg(all(unix, feature = "enabled"))]
+#[inline]
+pub fn connect_unix(path: impl AsRef<Path>) -> RemoteDebug {
+    RemoteDebug::from_endpoint(|_| Endpoint::ConnectUnix(path.as_ref().to_path_buf()))
 }
 
-impl RemoteDebug {
-    fn from_sender(sender: SyncSender<Event>) -> Self {
-        Self {
-            sender,
-            flush: Flushed::new(),
-        }
-    }
+#[cfg(all(unix, not(feature = "enabled")))]
+#[inline]
+pub fn connect_unix(_path: impl AsRef<Path>) {}
 
-    fn from_port(port: u16) -> Self {
-        // Panic if mutex is poisoned
-        let remote_debug = &mut *REMOTE_DEBUG.lock().unwrap();
+/// Tag messages sent to the default port with a channel name (ex: "db", "http") so viewers can
+/// group or filter them. To tag messages sent elsewhere, use `with_tag` on the value returned by
+/// [port], [connect], etc.
+///
+/// ```dontrun
+/// let db = rdbg::tag("db");
+/// rdbg::msg!(&db, ["Connected to {}", url]);
+///
+/// rdbg::vals!(rdbg::port(5000).with_tag("http"), [status]);
+/// ```
+#[cfg(feature = "enabled")]
+#[inline]
+pub fn tag(tag: &str) -> RemoteDebug {
+    RemoteDebug::default_ref().with_tag(tag)
+}
 
-        // If our global var is already inited, just return it otherwise do one time thread creation
-        match remote_debug {
-            Some(remote_debug) => remote_debug.clone(),
-            None => {
-                let debug = handle_connections(port);
-                *remote_debug = Some(debug.clone());
-                debug
-            }
-        }
-    }
+#[cfg(not(feature = "enabled"))]
+#[inline]
+pub fn tag(_tag: &str) {}
 
-    pub fn send_message(&self, filename: &str, line: u32, payload: MsgPayload) {
-        if let Err(err) = self
-            .sender
-            .send(Event::NewMessage(Message::new(filename, line, payload)))
-        {
-            eprintln!("Unable to send new message event: {err}");
-        }
+/// Tag all messages sent from the current thread that don't have an explicit tag until the
+/// returned guard is dropped, at which point the previous scoped tag (if any) is restored.
+///
+/// ```dontrun
+/// let _tag = rdbg::scoped_tag("cache");
+/// // Both of these are tagged "cache"
+/// rdbg::msg!("Evicting {}", key);
+/// rdbg::vals!(rdbg::port(5000), [key]);
+/// ```
+#[cfg(feature = "enabled")]
+#[inline]
+pub fn scoped_tag(tag: &str) -> ScopedTag {
+    let prev = SCOPED_TAG.with(|scoped| scoped.replace(Some(tag.into())));
+    ScopedTag {
+        prev,
+        _not_send: PhantomData,
     }
+}
 
-    #[inline]
-    pub fn flush(&self) {
-        self.flush.flush_and_wait(&self.sender);
-    }
+#[cfg(not(feature = "enabled"))]
+#[inline]
+pub fn scoped_tag(_tag: &str) {}
+
+// *** ScopedTag ***
+
+/// Guard returned by [scoped_tag] that restores the previous scoped tag when dropped
+#[must_use = "the scoped tag is removed as soon as this is dropped"]
+pub struct ScopedTag {
+    prev: Option<Arc<str>>,
+    // The tag is per-thread, so the guard must be dropped on the thread that created it
+    _not_send: PhantomData<*const ()>,
 }
 
-impl Default for RemoteDebug {
-    fn default() -> Self {
-        Self::from_port(DEFAULT_PORT)
+impl Drop for ScopedTag {
+    fn drop(&mut self) {
+        SCOPED_TAG.with(|scoped| *scoped.borrow_mut() = self.prev.take());
     }
 }
 
-// NOTE: This function isn't a part of RemoteDebug simply to make it a few less key strokes for the
-// user in case they want to include this on every macro invocation.
-/// Specify a custom port for the TCP socket to listen on when using the [msg] and [vals] macros.
+/// Change what happens when a message is sent to the default port but its queue is full. By
+/// default, the sender waits until there is room (see [Policy] for the alternatives).
 ///
-/// NOTE: The first time this function or [msg] or [vals] macros are processed determines the port.
-/// Once it is established it will not change no matter what `port` value is used.
+/// Any messages that are dropped are counted and reported to the viewer.
 ///
 /// ```dontrun
-/// let world = "world!";
-/// rdbg::msg!(rdbg::port(5000), ["Hello {}", world]);
+/// rdbg::policy(rdbg::Policy::DropOldest);
+/// rdbg::port(5000).set_policy(rdbg::Policy::Timeout(Duration::from_millis(10)));
+/// ```
+#[cfg(feature = "enabled")]
+#[inline]
+pub fn policy(policy: Policy) {
+    RemoteDebug::default_ref().set_policy(policy);
+}
+
+#[cfg(not(feature = "enabled"))]
+#[inline]
+pub fn policy(_policy: Policy) {}
+
+/// Keep recent history for the default port and replay it to each newly connected viewer. This
+/// makes it possible to attach a viewer after a problem occurs and still see what led up to it.
 ///
-/// rdbg::vals!(rdbg::port(5000), [world, 1 + 1]);
-/// rdbg::flush();
+/// NOTE: While history is kept, messages no longer wait for a viewer to be attached, however,
+/// [flush] still waits for one.
+///
+/// ```dontrun
+/// rdbg::history(rdbg::History::Messages(1000));
+/// rdbg::port(5000).set_history(rdbg::History::Bytes(64 * 1024));
 /// ```
 #[cfg(feature = "enabled")]
 #[inline]
-pub fn port(port: u16) -> RemoteDebug {
-    RemoteDebug::from_port(port)
+pub fn history(history: History) {
+    RemoteDebug::default_ref().set_history(history);
 }
 
 #[cfg(not(feature = "enabled"))]
 #[inline]
-pub fn port(_port: u16) {}
+pub fn history(_history: History) {}
+
+/// Install a panic hook that sends each panic (with its message, location, thread name and a
+/// backtrace) to the default port and then flushes it, so messages sent just before the panic
+/// aren't lost. Afterwards, the previously installed hook is called as usual.
+///
+/// To send panics to a custom port, use its `install_panic_hook` method instead (both can be
+/// used together).
+///
+/// NOTE: To avoid hanging a panicking program, sending and flushing each give up after a second
+/// if no viewer is receiving messages.
+///
+/// ```dontrun
+/// rdbg::install_panic_hook();
+/// rdbg::port(5000).install_panic_hook();
+/// ```
+#[cfg(feature = "enabled")]
+#[inline]
+pub fn install_panic_hook() {
+    RemoteDebug::default_ref().install_panic_hook();
+}
+
+#[cfg(not(feature = "enabled"))]
+#[inline]
+pub fn install_panic_hook() {}
 
 /// Flush msg/val queue for default port
 ///
-/// NOTE: The first time this function or [msg] or [vals] macros are processed determines the port.
-/// Once it is established it will not change even if custom port macros or functions are called.
+/// NOTE: This only flushes the default port. Custom ports must be flushed via their own `flush`
+/// method.
 ///
 /// ```dontrun
 /// let world = "world!";
-/// rdbg::msg!(rdbg::port(5000), ["Hello {}", world]);
+/// rdbg::msg!("Hello {}", world);
 ///
-/// rdbg::vals!(rdbg::port(5000), [world, 1 + 1]);
+/// rdbg::vals!(world, 1 + 1);
 /// rdbg::flush();
 /// ```
 #[cfg(feature = "enabled")]
 #[inline]
 pub fn flush() {
-    RemoteDebug::default().flush();
+    RemoteDebug::default_ref().flush();
 }
 
 #[cfg(not(feature = "enabled"))]
 #[inline]
 pub fn flush() {}
 
+// *** Endpoint ***
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Endpoint {
+    // Listen for viewers
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    // Connect out to a listening viewer
+    ConnectTcp(String),
+    // Only created by `connect_unix`
+    #[cfg(all(unix, feature = "enabled"))]
+    ConnectUnix(PathBuf),
+}
+
+impl Endpoint {
+    fn is_listening(&self) -> bool {
+        match self {
+            Endpoint::Tcp(_) => true,
+            #[cfg(unix)]
+            Endpoint::Unix(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => Display::fmt(addr, f),
+            Endpoint::ConnectTcp(addr) => f.write_str(addr),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+            #[cfg(all(unix, feature = "enabled"))]
+            Endpoint::ConnectUnix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+// *** Listener ***
+
+enum Listener {
+    Tcp(TcpListener),
+    // The path is removed when the listener is dropped
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(Listener::Unix(bind_private_socket(path)?, path.clone()))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a listening endpoint",
+            )),
+        }
+    }
+
+    fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// Binds inside a directory only we can access and moves the socket into place once its own
+// permissions are restricted, so there is never a window where someone else can connect
+#[cfg(unix)]
+fn bind_private_socket(path: &Path) -> io::Result<UnixListener> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    // Unique per bind, but a process that crashed part way through may have left one behind
+    // (and its PID may since have been reused), so skip over any that already exist
+    let dir = loop {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let dir = path.with_file_name(format!(".rdbg-{}-{id}", process::id()));
+
+        match DirBuilder::new().mode(PRIVATE_DIR_PERMISSIONS).create(&dir) {
+            Ok(()) => break dir,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+    };
+    let tmp_path = dir.join("sock");
+
+    let result = UnixListener::bind(&tmp_path).and_then(|listener| {
+        fs::set_permissions(&tmp_path, Permissions::from_mode(SOCKET_PERMISSIONS))?;
+        fs::rename(&tmp_path, path)?;
+        Ok(listener)
+    });
+
+    let _ = fs::remove_file(&tmp_path);
+    let _ = fs::remove_dir(&dir);
+    result
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    // Only remove sockets - anything else at this path is likely a mistake and binding will fail
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            // Left behind by a process that exited without cleaning up, unless something answers
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "Another process is listening on this path",
+                ));
+            }
+            fs::remove_file(path)
+        }
+        _ => Ok(()),
+    }
+}
+
+// *** Stream ***
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn connect(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::ConnectTcp(addr) => Ok(Stream::Tcp(TcpStream::connect(addr.as_str())?)),
+            #[cfg(all(unix, feature = "enabled"))]
+            Endpoint::ConnectUnix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a connecting endpoint",
+            )),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
 // *** Connection related functions ***
 
-fn handle_connections(port: u16) -> RemoteDebug {
-    let (sender, receiver) = sync_channel::<Event>(CHAN_MAX_MESSAGES);
-    let debug = RemoteDebug::from_sender(sender);
-    let debug_clone = debug.clone();
+fn handle_connections(endpoint: Endpoint, config: &Config) -> RemoteDebug {
+    // Build the hello record now so its start time isn't when the first viewer connected
+    hello();
 
+    let queue = Arc::new(Queue::new(config.policy));
+    let debug = RemoteDebug::from_queue(queue.clone());
+    let mut dispatcher = Dispatcher::new(config.history, config.policy, debug.flush.clone());
+
+    let queue_clone = queue.clone();
     thread::spawn(move || {
-        let mut curr_msg = None;
+        if endpoint.is_listening() {
+            accept_clients(&endpoint, &queue_clone)
+        } else {
+            connect_clients(&endpoint, &queue_clone)
+        }
+    });
+    thread::spawn(move || dispatcher.run(&queue));
+
+    debug
+}
+
+fn accept_clients(endpoint: &Endpoint, queue: &Queue) {
+    // We have no good way to report errors, so just exit if we can't listen
+    // (likely due to 'address in use' or 'permission denied', so we want to know about that
+    // not mysteriously just not receive messages)
+    match Listener::bind(endpoint) {
+        Ok(listener) => loop {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            // If we can't clone the stream we can't manage it, so just drop the connection
+            if let Ok((client, receiver)) = Client::new(&stream) {
+                // Register before the version is written so the viewer can't miss any
+                // messages sent once it considers itself connected
+                queue.push_client(client);
+                thread::spawn(move || process_stream(stream, &receiver));
+            }
+        },
+        Err(err) => {
+            eprintln!("Unable to listen on {endpoint}: {err}");
+            // We exit instead of panic because this is a separate thread. We want it very
+            // obvious if for some reason it can't listen on this endpoint so we exit immediately
+            exit(1);
+        }
+    }
+}
+
+fn connect_clients(endpoint: &Endpoint, queue: &Queue) {
+    loop {
+        if let Ok(stream) = Stream::connect(endpoint) {
+            // If we can't clone the stream we can't manage it, so just drop the connection
+            if let Ok((client, receiver)) = Client::new(&stream) {
+                queue.push_client(client);
+
+                // We only keep one connection at a time, so wait until this one is done
+                process_stream(stream, &receiver);
+            }
+        }
+
+        thread::sleep(Duration::from_millis(CONNECT_WAIT_TIME));
+    }
+}
+
+// *** Dispatcher ***
+
+// The viewers a flush was sent to, each with the ack it will drop once caught up
+type FlushAcks = Vec<(Arc<ClientQueue>, Receiver<()>)>;
+
+struct Dispatcher {
+    clients: Vec<Client>,
+    history: HistoryBuffer,
+    // Events that couldn't be delivered because no viewers were attached
+    pending: VecDeque<Event>,
+    policy: Policy,
+    // Flushes are waited on by their own thread so a slow viewer can't hold up dispatching
+    flushes: Sender<(u64, FlushAcks)>,
+}
+
+impl Dispatcher {
+    fn new(history: History, policy: Policy, flush: Flushed) -> Self {
+        let (flushes, receiver) = channel::<(u64, FlushAcks)>();
+        thread::spawn(move || {
+            for (generation, acks) in receiver {
+                for (client, ack) in acks {
+                    client.wait_for(&ack);
+                }
+                flush.flushed(generation);
+            }
+        });
+
+        Self {
+            clients: Vec::new(),
+            history: HistoryBuffer::new(history),
+            pending: VecDeque::new(),
+            policy,
+            flushes,
+        }
+    }
 
+    fn run(&mut self, queue: &Queue) {
         loop {
-            // We have no good way to report errors, so just unwrap and panic, if needed
-            // (likely due to 'address in use' or 'permission denied', so we want to know about that
-            // not mysteriously just not receive messages)
-            match TcpListener::bind((BIND_ADDR, port)) {
-                Ok(listener) => {
-                    // Per docs, 'incoming' will always return an entry
-                    if let Ok(mut stream) = listener.incoming().next().unwrap() {
-                        if process_stream(&mut stream, &receiver, &mut curr_msg, &debug_clone) {
-                            // Quit signalled - we are done
-                            break;
-                        }
-                    }
+            if !self.clients.is_empty() {
+                if let Some(event) = self.pending.pop_front() {
+                    self.dispatch(event);
+                    continue;
                 }
-                Err(err) => {
-                    eprintln!("Unable to listen on {BIND_ADDR}:{port}: {err}");
-                    // We exit instead of panic because this is a separate thread. We want it very
-                    // obvious if for some reason it can't listen on this port so we exit immediately
-                    exit(1);
+            }
+
+            // Until at least one viewer is attached, we leave events queued so they aren't lost
+            // (unless we are keeping history, since then they will be replayed from there)
+            let hold_events =
+                self.clients.is_empty() && (!self.pending.is_empty() || !self.history.is_enabled());
+
+            match queue.next(hold_events) {
+                Input::Client(client) => self.add_client(client),
+                Input::SetHistory(history) => self.history.set_limit(history),
+                Input::SetPolicy(policy) => self.policy = policy,
+                Input::Dropped(dropped) => {
+                    self.dispatch(Event::NewMessage(Arc::new(Message::new(
+                        __callsite!(),
+                        Level::Warn,
+                        None,
+                        MsgPayload::Dropped(dropped),
+                    ))))
                 }
+                Input::Event(event) => self.dispatch(event),
             }
         }
-    });
+    }
 
-    debug
+    fn add_client(&mut self, mut client: Client) {
+        // Anything a viewer that has since gone away never got goes to this one instead
+        self.retain_clients(|client| !client.queue.is_closed());
+
+        // Bring the new viewer up to date before it receives any live messages
+        if self.history.is_enabled() {
+            let msgs = self.history.messages();
+            let marker = Message::new(
+                __callsite!(),
+                Level::Info,
+                None,
+                MsgPayload::HistoryEnd(msgs.len() as u64),
+            );
+
+            if !client.send(ClientEvent::Replay(msgs, Arc::new(marker)), self.policy) {
+                return;
+            }
+        }
+
+        self.clients.push(client);
+    }
+
+    // Removes the viewers `keep` rejects. If that leaves none, the messages they never got are
+    // held for the next one (unless history is kept, since then they will be replayed from there).
+    fn retain_clients(&mut self, mut keep: impl FnMut(&mut Client) -> bool) {
+        let mut unsent = Vec::new();
+        self.clients.retain_mut(|client| {
+            let keep = keep(client);
+            if !keep {
+                let msgs = client.take_unsent();
+                // The viewer that got the least is the one to catch up from
+                if msgs.len() > unsent.len() {
+                    unsent = msgs;
+                }
+            }
+            keep
+        });
+
+        if self.clients.is_empty() && !self.history.is_enabled() {
+            self.pending
+                .extend(unsent.into_iter().map(Event::NewMessage));
+        }
+    }
+
+    #[inline]
+    fn broadcast(&mut self, event: impl Fn() -> ClientEvent) {
+        let policy = self.policy;
+        self.retain_clients(|client| client.send(event(), policy));
+    }
+
+    fn dispatch(&mut self, event: Event) {
+        match event {
+            Event::NewMessage(msg) => {
+                self.history.push(&msg);
+                self.broadcast(|| ClientEvent::NewMessage(msg.clone()));
+
+                // If history is kept, it will be replayed from there instead
+                if self.clients.is_empty() && !self.history.is_enabled() {
+                    self.pending.push_back(Event::NewMessage(msg));
+                }
+            }
+            Event::Flush(generation) => {
+                let mut acks = Vec::with_capacity(self.clients.len());
+                let policy = self.policy;
+                self.retain_clients(|client| {
+                    let (ack_sender, ack_receiver) = channel();
+                    let sent = client.send(ClientEvent::Flush(ack_sender), policy);
+                    if sent {
+                        acks.push((client.queue.clone(), ack_receiver));
+                    }
+                    sent
+                });
+
+                if self.clients.is_empty() {
+                    self.pending.push_back(Event::Flush(generation));
+                } else {
+                    // Can't fail - the flush thread only exits once we are dropped
+                    let _ = self.flushes.send((generation, acks));
+                }
+            }
+        }
+    }
 }
 
-fn process_stream(
-    stream: &mut TcpStream,
-    receiver: &Receiver<Event>,
-    curr_msg: &mut Option<Message>,
-    debug: &RemoteDebug,
-) -> bool {
-    // If we hit an error writing out the version just return since we have no good way to report
-    if write_to_stream(&WIRE_PROTOCOL_VERSION.to_be_bytes(), stream).is_err() {
-        return false;
+fn process_stream(mut stream: Stream, receiver: &ClientReceiver) {
+    // If we hit an error writing out the version or hello just return since we have no good way
+    // to report
+    if write_to_stream(&WIRE_PROTOCOL_VERSION.to_be_bytes(), &mut stream).is_err()
+        || write_to_stream(hello().as_slice(), &mut stream).is_err()
+    {
+        return;
     }
 
-    loop {
-        // If we were interrupted sending last message then resend otherwise wait for a new message
-        let msg = match &curr_msg {
-            Some(msg) => msg,
-            None => {
-                // We have no good way to report errors, so just unwrap and panic, if needed
-                // (this is likely impossible since our SyncSender is in a global var)
-                match receiver.recv().unwrap() {
-                    Event::NewMessage(msg) => {
-                        *curr_msg = Some(msg);
-                        // Can't fail, stored above
-                        curr_msg.as_ref().unwrap()
+    let mut batch = Batch::new(receiver.queue.written.clone());
+
+    // Once the dispatcher is done with this viewer (or we hit a write error) we are done too
+    'events: while let Some(event) = receiver.recv() {
+        let mut next = Some(event);
+
+        // Drain everything already queued into the batch, only writing early if it gets too big
+        // or too old, so a busy sender makes one write call for many messages
+        while let Some(event) = next {
+            match event {
+                ClientEvent::NewMessage(msg) => batch.push(msg),
+                ClientEvent::Replay(msgs, marker) => {
+                    for msg in msgs.into_iter().chain([marker]) {
+                        batch.push(msg);
                     }
-                    Event::Flush => {
-                        debug.flush.flushed();
-                        continue;
+                }
+                ClientEvent::Flush(ack) => {
+                    // Everything before the flush must be written before the ack is dropped
+                    if batch.write(&mut stream).is_err() {
+                        break 'events;
                     }
+                    drop(ack);
                 }
             }
-        };
 
-        match write_to_stream(msg.as_slice(), stream) {
-            Ok(_) => {
-                // Success, don't resend this message again
-                *curr_msg = None;
+            if batch.is_due() && batch.write(&mut stream).is_err() {
+                break 'events;
             }
-            Err(_) => {
-                // Preserve current message and resend on next connection
-                break;
+            next = receiver.try_recv();
+        }
+
+        // Nothing else is queued, so write what we have rather than wait for more
+        if batch.write(&mut stream).is_err() {
+            break;
+        }
+    }
+
+    // Whatever we couldn't write can still be sent to the next viewer
+    receiver.unsent(batch.take_unwritten());
+}
+
+// *** Batch ***
+
+// Messages waiting to be written to a single viewer as one write
+struct Batch {
+    buffer: Vec<u8>,
+    // The messages in the buffer, kept in case it can't be written
+    msgs: Vec<Arc<Message>>,
+    // When the first message still in the buffer was added
+    started: Option<Instant>,
+    // The IDs of the callsites this viewer has been sent the definition of
+    sent_callsites: HashSet<u32>,
+    // Bytes written so far
+    written: Arc<AtomicU64>,
+}
+
+impl Batch {
+    fn new(written: Arc<AtomicU64>) -> Self {
+        Self {
+            buffer: Vec::with_capacity(BATCH_MAX_BYTES),
+            msgs: Vec::new(),
+            started: None,
+            sent_callsites: HashSet::new(),
+            written,
+        }
+    }
+
+    // Adds a message, preceded by the definition of its callsite if this viewer doesn't have it yet
+    fn push(&mut self, msg: Arc<Message>) {
+        if let Some(callsite) = msg.callsite {
+            if self.sent_callsites.insert(callsite.id) {
+                self.buffer.extend_from_slice(callsite.record.as_slice());
             }
         }
+
+        self.buffer.extend_from_slice(msg.as_slice());
+        self.msgs.push(msg);
+        self.started.get_or_insert_with(Instant::now);
+    }
+
+    fn is_due(&self) -> bool {
+        self.buffer.len() >= BATCH_MAX_BYTES
+            || self.started.is_some_and(|started| {
+                started.elapsed() >= Duration::from_millis(BATCH_MAX_LATENCY)
+            })
+    }
+
+    fn write(&mut self, stream: &mut Stream) -> io::Result<()> {
+        let mut index = 0;
+
+        // Like write_to_stream, but progress is reported as we go
+        while index < self.buffer.len() {
+            let wrote = stream.write(&self.buffer[index..])?;
+            index += wrote;
+            self.written.fetch_add(wrote as u64, Ordering::Relaxed);
+        }
+
+        self.buffer.clear();
+        self.msgs.clear();
+        self.started = None;
+        Ok(())
     }
 
-    false
+    #[inline]
+    fn take_unwritten(&mut self) -> Vec<Arc<Message>> {
+        mem::take(&mut self.msgs)
+    }
 }
 
-fn write_to_stream(buffer: &[u8], stream: &mut TcpStream) -> io::Result<()> {
+fn write_to_stream(buffer: &[u8], stream: &mut Stream) -> io::Result<()> {
     let mut index = 0;
 
     // Keep writing until everything in the buffer has been written or we get an error
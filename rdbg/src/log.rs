@@ -0,0 +1,124 @@
+//! A [log](https://docs.rs/log) backend that forwards records to the remote viewer
+//!
+//! ```dontrun
+//! rdbg::log::init().unwrap();
+//! log::info!("Hello {}", "world!");
+//!
+//! // Or send to a custom port and only forward warnings and errors
+//! rdbg::log::Logger::new(rdbg::port(5000))
+//!     .init(log::LevelFilter::Warn)
+//!     .unwrap();
+//! ```
+
+use ::log::SetLoggerError;
+#[cfg(feature = "enabled")]
+use ::log::{LevelFilter, Metadata, Record};
+
+use crate::Level;
+#[cfg(feature = "enabled")]
+use crate::{level_enabled, Callsite, MsgPayload, RemoteDebug, STATIC_MAX_LEVEL};
+
+// *** Level conversion ***
+
+impl From<::log::Level> for Level {
+    fn from(level: ::log::Level) -> Self {
+        match level {
+            ::log::Level::Error => Level::Error,
+            ::log::Level::Warn => Level::Warn,
+            ::log::Level::Info => Level::Info,
+            ::log::Level::Debug => Level::Debug,
+            ::log::Level::Trace => Level::Trace,
+        }
+    }
+}
+
+#[cfg(feature = "enabled")]
+fn static_max_level() -> LevelFilter {
+    match STATIC_MAX_LEVEL {
+        None => LevelFilter::Off,
+        Some(Level::Error) => LevelFilter::Error,
+        Some(Level::Warn) => LevelFilter::Warn,
+        Some(Level::Info) => LevelFilter::Info,
+        Some(Level::Debug) => LevelFilter::Debug,
+        Some(Level::Trace) => LevelFilter::Trace,
+    }
+}
+
+// *** Logger ***
+
+/// A [log::Log] implementation that sends each record as a message. The record's target is
+/// used as the message tag.
+#[cfg(feature = "enabled")]
+pub struct Logger {
+    debug: RemoteDebug,
+}
+
+#[cfg(feature = "enabled")]
+impl Logger {
+    /// Create a logger that sends to `debug` (ex: the value returned by [crate::port])
+    pub fn new(debug: RemoteDebug) -> Self {
+        Self { debug }
+    }
+
+    /// Install this as the global logger, forwarding records at `level` or more severe (further
+    /// limited by any `max_level_*` feature)
+    pub fn init(self, level: LevelFilter) -> Result<(), SetLoggerError> {
+        ::log::set_boxed_logger(Box::new(self))?;
+        ::log::set_max_level(level.min(static_max_level()));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "enabled")]
+impl Default for Logger {
+    fn default() -> Self {
+        Self::new(RemoteDebug::default())
+    }
+}
+
+#[cfg(feature = "enabled")]
+impl ::log::Log for Logger {
+    #[inline]
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        level_enabled(metadata.level().into())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let callsite = Callsite::dynamic(
+                record.file().unwrap_or_default(),
+                record.line().unwrap_or_default(),
+                0,
+                record.module_path().unwrap_or_default(),
+            );
+
+            self.debug.send_tagged_message(
+                callsite,
+                record.level().into(),
+                Some(record.target()),
+                // Several records can share a dynamic callsite, so the text is always sent
+                MsgPayload::Message(std::fmt::format(*record.args()).into()),
+            );
+        }
+    }
+
+    /// NOTE: Like [crate::flush], this waits until a viewer has received all queued messages
+    #[inline]
+    fn flush(&self) {
+        self.debug.flush();
+    }
+}
+
+/// Install a [Logger] for the default port as the global logger, forwarding all records (limited
+/// only by any `max_level_*` feature)
+#[cfg(feature = "enabled")]
+#[inline]
+pub fn init() -> Result<(), SetLoggerError> {
+    Logger::default().init(LevelFilter::Trace)
+}
+
+#[cfg(not(feature = "enabled"))]
+#[inline]
+pub fn init() -> Result<(), SetLoggerError> {
+    Ok(())
+}
@@ -0,0 +1,166 @@
+// A bounded lock-free MPMC ring buffer (Dmitry Vyukov's design). Each thread sending messages
+// owns one of these and is normally its only producer, while the dispatcher thread drains it.
+// Multiple consumers are needed because a producer using the drop oldest policy pops its own
+// oldest message to make room.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// *** CachePadded ***
+
+// Keeps the producer and consumer positions on separate cache lines so they don't contend
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+// *** Slot ***
+
+struct Slot<T> {
+    // Equal to the position when empty and ready to be written, one past it when full
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// *** Ring ***
+
+pub(crate) struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+    mask: usize,
+    push_pos: CachePadded<AtomicUsize>,
+    pop_pos: CachePadded<AtomicUsize>,
+}
+
+// Values are moved between threads, but never shared, so `T: Sync` isn't needed
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    // Panics if `capacity` isn't a power of two
+    pub(crate) fn new(capacity: usize) -> Self {
+        assert!(
+            capacity.is_power_of_two(),
+            "Capacity must be a power of two"
+        );
+
+        let slots = (0..capacity)
+            .map(|pos| Slot {
+                sequence: AtomicUsize::new(pos),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+            })
+            .collect();
+
+        Self {
+            slots,
+            mask: capacity - 1,
+            push_pos: CachePadded(AtomicUsize::new(0)),
+            pop_pos: CachePadded(AtomicUsize::new(0)),
+        }
+    }
+
+    // Returns the value back if the ring is full
+    pub(crate) fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.push_pos.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+
+            match (sequence as isize).wrapping_sub(pos as isize) {
+                // The slot is empty - try to claim it
+                0 => match self.push_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: Winning the CAS gives us exclusive access to this slot until
+                        // its sequence is published below
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                },
+                // The slot still holds a value from the previous lap, so we are full
+                diff if diff < 0 => return Err(value),
+                // Another producer claimed this position first
+                _ => pos = self.push_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    pub(crate) fn pop(&self) -> Option<T> {
+        let mut pos = self.pop_pos.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+
+            match (sequence as isize).wrapping_sub(pos.wrapping_add(1) as isize) {
+                // The slot is full - try to claim it
+                0 => match self.pop_pos.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // SAFETY: Winning the CAS gives us exclusive access to this slot, and its
+                        // sequence shows the value was fully written
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        // Mark the slot empty and ready for the next lap
+                        slot.sequence
+                            .store(pos.wrapping_add(self.capacity()), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => pos = current,
+                },
+                // The slot hasn't been written yet, so we are empty
+                diff if diff < 0 => return None,
+                // Another consumer claimed this position first
+                _ => pos = self.pop_pos.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    // Only a hint when other threads are pushing or popping concurrently
+    pub(crate) fn len(&self) -> usize {
+        let push_pos = self.push_pos.load(Ordering::Acquire);
+        let pop_pos = self.pop_pos.load(Ordering::Acquire);
+
+        // A pop after we read the push position can make this negative
+        (push_pos.wrapping_sub(pop_pos) as isize).clamp(0, self.capacity() as isize) as usize
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub(crate) fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
+    #[inline]
+    pub(crate) fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
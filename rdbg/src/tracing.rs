@@ -0,0 +1,187 @@
+//! A [tracing](https://docs.rs/tracing) layer that streams spans and events to the remote viewer
+//!
+//! ```dontrun
+//! use tracing_subscriber::prelude::*;
+//!
+//! tracing_subscriber::registry()
+//!     .with(rdbg::tracing::Layer::default())
+//!     .init();
+//!
+//! let span = tracing::info_span!("request", id = 42);
+//! let _enter = span.enter();
+//! tracing::info!(status = 200, "Hello {}", "world!");
+//! ```
+
+use std::fmt::Debug;
+
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Metadata, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::{level_enabled, Callsite, Level, MsgPayload, RemoteDebug};
+
+// *** Level conversion ***
+
+impl From<&tracing_core::Level> for Level {
+    fn from(level: &tracing_core::Level) -> Self {
+        match *level {
+            tracing_core::Level::ERROR => Level::Error,
+            tracing_core::Level::WARN => Level::Warn,
+            tracing_core::Level::INFO => Level::Info,
+            tracing_core::Level::DEBUG => Level::Debug,
+            tracing_core::Level::TRACE => Level::Trace,
+        }
+    }
+}
+
+// *** FieldVisitor ***
+
+#[derive(Default)]
+struct FieldVisitor(Vec<(&'static str, String)>);
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        // Avoid the quotes debug formatting would add
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.push((field.name(), format!("{value:?}")));
+    }
+}
+
+// *** Layer ***
+
+/// A [tracing_subscriber::Layer] that sends span lifecycle and events as messages. Span IDs and
+/// parents are included so the viewer can rebuild the call tree. The target is used as the
+/// message tag.
+pub struct Layer {
+    debug: RemoteDebug,
+}
+
+impl Layer {
+    /// Create a layer that sends to `debug` (ex: the value returned by [crate::port])
+    #[cfg(feature = "enabled")]
+    pub fn new(debug: RemoteDebug) -> Self {
+        Self { debug }
+    }
+
+    fn send(&self, metadata: &Metadata<'_>, payload: MsgPayload) {
+        let callsite = Callsite::dynamic(
+            metadata.file().unwrap_or_default(),
+            metadata.line().unwrap_or_default(),
+            0,
+            metadata.module_path().unwrap_or_default(),
+        );
+
+        self.debug.send_tagged_message(
+            callsite,
+            metadata.level().into(),
+            Some(metadata.target()),
+            payload,
+        );
+    }
+
+    // Spans and events are still seen by other layers, so we filter here rather than in `enabled`
+    #[inline]
+    fn is_enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.debug.is_active() && level_enabled(metadata.level().into())
+    }
+
+    fn send_for_span<S>(&self, id: &Id, ctx: &Context<'_, S>, payload: impl FnOnce() -> MsgPayload)
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if let Some(span) = ctx.span(id) {
+            if self.is_enabled(span.metadata()) {
+                self.send(span.metadata(), payload());
+            }
+        }
+    }
+}
+
+impl Default for Layer {
+    #[cfg(feature = "enabled")]
+    fn default() -> Self {
+        Self::new(RemoteDebug::default())
+    }
+
+    #[cfg(not(feature = "enabled"))]
+    fn default() -> Self {
+        Self {
+            debug: RemoteDebug::disabled(),
+        }
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for Layer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !self.is_enabled(attrs.metadata()) {
+            return;
+        }
+
+        let parent = ctx
+            .span(id)
+            .and_then(|span| span.parent())
+            .map(|parent| parent.id().into_u64());
+        let mut fields = FieldVisitor::default();
+        attrs.record(&mut fields);
+
+        self.send(
+            attrs.metadata(),
+            MsgPayload::SpanNew {
+                id: id.into_u64(),
+                parent: parent.unwrap_or_default(),
+                name: attrs.metadata().name(),
+                fields: fields.0,
+            },
+        );
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        self.send_for_span(id, &ctx, || {
+            let mut fields = FieldVisitor::default();
+            values.record(&mut fields);
+
+            MsgPayload::SpanRecord {
+                id: id.into_u64(),
+                fields: fields.0,
+            }
+        });
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if !self.is_enabled(event.metadata()) {
+            return;
+        }
+
+        let span = ctx.event_span(event).map(|span| span.id().into_u64());
+        let mut fields = FieldVisitor::default();
+        event.record(&mut fields);
+
+        self.send(
+            event.metadata(),
+            MsgPayload::Event {
+                span: span.unwrap_or_default(),
+                fields: fields.0,
+            },
+        );
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.send_for_span(id, &ctx, || MsgPayload::SpanEnter(id.into_u64()));
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.send_for_span(id, &ctx, || MsgPayload::SpanExit(id.into_u64()));
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        self.send_for_span(&id, &ctx, || MsgPayload::SpanClose(id.into_u64()));
+    }
+}
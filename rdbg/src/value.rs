@@ -0,0 +1,557 @@
+// A serde serializer that writes values in a self-describing binary encoding so the viewer can
+// rebuild them as a tree. Each value starts with a `ValueType` byte followed by its data, using the
+// same big-endian, length prefixed conventions as the rest of the wire protocol.
+
+use std::fmt::Display;
+
+use serde::ser::{
+    self, Serialize, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant,
+    SerializeTuple, SerializeTupleStruct, SerializeTupleVariant,
+};
+
+// *** ValueType ***
+
+#[repr(u8)]
+enum ValueType {
+    Unit = 1,
+    Bool = 2,
+    I64 = 3,
+    U64 = 4,
+    F64 = 5,
+    Char = 6,
+    Str = 7,
+    Bytes = 8,
+    None = 9,
+    Some = 10,
+    // Sequences and tuples
+    Seq = 11,
+    Map = 12,
+    // Structs with named fields and unit structs
+    Struct = 13,
+    TupleStruct = 14,
+    // An enum variant: the enum name, variant name, then the value (unit, newtype, seq or struct)
+    Variant = 15,
+}
+
+// Values nested deeper than this are replaced by `TRUNCATED` (the viewer rejects values nested
+// more than 128 deep, so this leaves plenty of margin)
+const MAX_DEPTH: usize = 64;
+const TRUNCATED: &str = "<max depth exceeded>";
+
+// *** Error ***
+
+#[derive(Debug)]
+struct Error(String);
+
+impl Display for Error {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+// *** encode_value ***
+
+#[doc(hidden)]
+pub fn encode_value<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+    let mut encoder = Encoder::default();
+
+    match value.serialize(&mut encoder) {
+        Ok(()) => encoder.bytes,
+        // Serialize impls are allowed to fail, but we still want to tell the viewer something
+        Err(err) => {
+            let mut encoder = Encoder::default();
+            encoder.write_type(ValueType::Str);
+            encoder.write_str(&format!("<serialize error: {err}>"));
+            encoder.bytes
+        }
+    }
+}
+
+// *** Encoder ***
+
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+    // How deeply nested the value being written is (zero for the top level value)
+    depth: usize,
+}
+
+impl Encoder {
+    #[inline]
+    fn write_type(&mut self, value_type: ValueType) {
+        self.bytes.push(value_type as u8);
+    }
+
+    #[inline]
+    fn write_u32(&mut self, i: u32) {
+        self.bytes.extend(i.to_be_bytes());
+    }
+
+    #[inline]
+    fn write_u64(&mut self, i: u64) {
+        self.bytes.extend(i.to_be_bytes());
+    }
+
+    #[inline]
+    fn write_bytes(&mut self, b: &[u8]) {
+        self.write_u32(b.len() as u32);
+        self.bytes.extend(b);
+    }
+
+    #[inline]
+    fn write_str(&mut self, s: &str) {
+        self.write_bytes(s.as_bytes());
+    }
+
+    // Writes a placeholder element count that is filled in once the compound value is finished
+    // (serde doesn't always know the length up front)
+    fn start_compound(&mut self) -> Compound<'_> {
+        let count_pos = self.bytes.len();
+        self.write_u32(0);
+
+        Compound {
+            encoder: self,
+            count_pos: Some(count_pos),
+            count: 0,
+            variant_depth: 0,
+        }
+    }
+
+    #[inline]
+    fn write_truncated(&mut self) {
+        self.write_type(ValueType::Str);
+        self.write_str(TRUNCATED);
+    }
+
+    // Writes a value nested one level deeper than the current one, or a marker in its place if
+    // that would be too deep
+    fn nested<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        if self.depth >= MAX_DEPTH {
+            self.write_truncated();
+            return Ok(());
+        }
+
+        self.depth += 1;
+        let result = value.serialize(&mut *self);
+        self.depth -= 1;
+        result
+    }
+
+    // Starts the seq or struct holding the fields of a variant, which is nested one level deeper
+    // than the variant itself. If that would be too deep, a marker is written instead and the
+    // fields are skipped.
+    fn start_variant_compound(
+        &mut self,
+        start: impl FnOnce(&mut Self) -> Result<Compound<'_>, Error>,
+    ) -> Result<Compound<'_>, Error> {
+        if self.depth >= MAX_DEPTH {
+            self.write_truncated();
+            return Ok(Compound {
+                encoder: self,
+                count_pos: None,
+                count: 0,
+                variant_depth: 0,
+            });
+        }
+
+        self.depth += 1;
+        let mut compound = start(self)?;
+        compound.variant_depth = 1;
+        Ok(compound)
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Encoder {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.write_type(ValueType::Bool);
+        self.bytes.push(v as u8);
+        Ok(())
+    }
+
+    #[inline]
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    #[inline]
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    #[inline]
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.write_type(ValueType::I64);
+        self.write_u64(v as u64);
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), Error> {
+        // Too large values are sent as text rather than failing
+        match i64::try_from(v) {
+            Ok(v) => self.serialize_i64(v),
+            Err(_) => self.collect_str(&v),
+        }
+    }
+
+    #[inline]
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    #[inline]
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    #[inline]
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.write_type(ValueType::U64);
+        self.write_u64(v);
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), Error> {
+        // Too large values are sent as text rather than failing
+        match u64::try_from(v) {
+            Ok(v) => self.serialize_u64(v),
+            Err(_) => self.collect_str(&v),
+        }
+    }
+
+    #[inline]
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.write_type(ValueType::F64);
+        self.write_u64(v.to_bits());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.write_type(ValueType::Char);
+        self.write_u32(v as u32);
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.write_type(ValueType::Str);
+        self.write_str(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.write_type(ValueType::Bytes);
+        self.write_bytes(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.write_type(ValueType::None);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        self.write_type(ValueType::Some);
+        self.nested(value)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.write_type(ValueType::Unit);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<(), Error> {
+        self.write_type(ValueType::Struct);
+        self.write_str(name);
+        self.write_u32(0);
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.write_type(ValueType::Variant);
+        self.write_str(name);
+        self.write_str(variant);
+        self.nested(&())
+    }
+
+    // Newtypes are transparent, as they are in most serde formats
+    #[inline]
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.write_type(ValueType::Variant);
+        self.write_str(name);
+        self.write_str(variant);
+        self.nested(value)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'a>, Error> {
+        self.write_type(ValueType::Seq);
+        Ok(self.start_compound())
+    }
+
+    #[inline]
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.write_type(ValueType::TupleStruct);
+        self.write_str(name);
+        Ok(self.start_compound())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.write_type(ValueType::Variant);
+        self.write_str(name);
+        self.write_str(variant);
+        self.start_variant_compound(|encoder| encoder.serialize_seq(Some(len)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'a>, Error> {
+        self.write_type(ValueType::Map);
+        Ok(self.start_compound())
+    }
+
+    fn serialize_struct(self, name: &'static str, _len: usize) -> Result<Compound<'a>, Error> {
+        self.write_type(ValueType::Struct);
+        self.write_str(name);
+        Ok(self.start_compound())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.write_type(ValueType::Variant);
+        self.write_str(name);
+        self.write_str(variant);
+        self.start_variant_compound(|encoder| encoder.serialize_struct(variant, len))
+    }
+
+    fn collect_str<T: Display + ?Sized>(self, value: &T) -> Result<(), Error> {
+        self.serialize_str(&value.to_string())
+    }
+}
+
+// *** Compound ***
+
+struct Compound<'a> {
+    encoder: &'a mut Encoder,
+    // Where the element count goes (`None` if the elements are skipped for being too deep)
+    count_pos: Option<usize>,
+    // Number of elements, map entries or fields written so far
+    count: u32,
+    // Extra depth added for the fields of a variant, removed once finished
+    variant_depth: usize,
+}
+
+impl Compound<'_> {
+    #[inline]
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        if self.count_pos.is_none() {
+            return Ok(());
+        }
+
+        self.count += 1;
+        self.encoder.nested(value)
+    }
+
+    #[inline]
+    fn field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        if self.count_pos.is_some() {
+            self.encoder.write_str(key);
+        }
+        self.element(value)
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        if let Some(count_pos) = self.count_pos {
+            self.encoder.bytes[count_pos..count_pos + 4].copy_from_slice(&self.count.to_be_bytes());
+        }
+        self.encoder.depth -= self.variant_depth;
+        Ok(())
+    }
+}
+
+impl SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    // Only the value counts as a new entry
+    #[inline]
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        match self.count_pos {
+            Some(_) => self.encoder.nested(key),
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    #[inline]
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    #[inline]
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}